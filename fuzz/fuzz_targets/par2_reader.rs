@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use std::io::Cursor;
+
+use reverse_checksum_renamer::par2_reader;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = par2_reader::parse_par2(&mut Cursor::new(data), "fuzz.par2");
+});
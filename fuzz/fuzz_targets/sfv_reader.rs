@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use std::io::Cursor;
+
+use reverse_checksum_renamer::sfv_reader;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = sfv_reader::parse_sfv(&mut Cursor::new(data), "fuzz.sfv");
+});
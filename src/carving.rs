@@ -0,0 +1,376 @@
+// reverse-checksum-renamer
+//
+// Copyright (C) 2020  Martin Feil aka. SGDW
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>
+
+// Catalog entries at unknown offsets within larger files, e.g. recovered disk images
+// or tar archives without their headers. The CRC32 of the first PAR2 slice is rolled
+// over the file, entries without slice checksums are looked for at sector boundaries
+// by the MD5 of their first 16k. Every candidate is verified by its full digests.
+
+use std::collections::{BTreeMap, HashMap};
+use std::collections::btree_map::Entry;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+
+use concatenation::Piece;
+use file_verification;
+use file_verification::{ChecksumEntry, HashAlgorithm, Hasher};
+use hash_backend;
+use throttle;
+
+const SECTOR_SIZE: u64 = 512;
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+// Sequential reader returning single bytes
+struct ByteReader {
+    file: File,
+    buffer: Vec<u8>,
+    pos: usize,
+    len: usize,
+    throttled: bool, // false for reading the same data a second time
+}
+
+impl ByteReader {
+    fn open(file: &str, throttled: bool) -> Result<ByteReader, io::Error> {
+        Ok(ByteReader {
+            file: File::open(file)?,
+            buffer: vec![0u8; BUFFER_SIZE],
+            pos: 0,
+            len: 0,
+            throttled,
+        })
+    }
+
+    #[inline]
+    fn next(&mut self) -> Result<Option<u8>, io::Error> {
+        if self.pos == self.len {
+            self.len = self.file.read(&mut self.buffer)?;
+            self.pos = 0;
+            if self.throttled {
+                throttle::consume(self.len);
+            }
+            if self.len == 0 {
+                return Ok(None);
+            }
+        }
+        self.pos += 1;
+        Ok(Some(self.buffer[self.pos - 1]))
+    }
+}
+
+// Sequential reader returning the data at increasing offsets, each byte is read once
+struct SectorReader {
+    file: File,
+    data: Vec<u8>,
+    start: u64, // offset of data
+}
+
+impl SectorReader {
+    fn open(file: &str) -> Result<SectorReader, io::Error> {
+        Ok(SectorReader {
+            file: File::open(file)?,
+            data: Vec::new(),
+            start: 0,
+        })
+    }
+
+    // Up to length bytes, less at the end of the file
+    fn read_at(&mut self, offset: u64, length: usize) -> Result<&[u8], io::Error> {
+        if (offset - self.start) as usize + length > self.data.len() {
+            let skip = ((offset - self.start) as usize).min(self.data.len());
+            self.data.drain(..skip);
+            self.start += skip as u64;
+            let wanted = (offset - self.start) as usize + length;
+            while self.data.len() < wanted {
+                let old_len = self.data.len();
+                self.data.resize(old_len + BUFFER_SIZE, 0);
+                let n = self.file.read(&mut self.data[old_len..])?;
+                self.data.truncate(old_len + n);
+                throttle::consume(n);
+                if n == 0 {
+                    break;
+                }
+            }
+        }
+        let end = ((offset - self.start) as usize + length).min(self.data.len());
+        let begin = ((offset - self.start) as usize).min(end);
+        Ok(&self.data[begin..end])
+    }
+}
+
+// Table of the reflected CRC32 polynomial, for updating the CRC without init and final XOR
+fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    for (n, value) in table.iter_mut().enumerate() {
+        let mut c = n as u32;
+        for _ in 0..8 {
+            c = if c & 1 != 0 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+        }
+        *value = c;
+    }
+    table
+}
+
+// CRC32 of a number of zero bytes
+fn zeros_crc32(length: u64) -> u32 {
+    let chunk = vec![0u8; 64 * 1024];
+    let chunk_crc = hash_backend::crc32(&chunk);
+
+    let mut crc = 0;
+    let mut remaining = length;
+    while remaining >= chunk.len() as u64 {
+        crc = file_verification::crc32_combine(crc, chunk_crc, chunk.len() as u64);
+        remaining -= chunk.len() as u64;
+    }
+    file_verification::crc32_combine(crc, hash_backend::crc32(&chunk[..remaining as usize]), remaining)
+}
+
+// CRC32 rolled over all windows of one length. Without init and final XOR the CRC is
+// linear, so the byte leaving the window is removed by XOR of its contribution.
+struct Window {
+    length: u64,
+    padding: u64, // zero bytes the first slice of short entries is checksummed with
+    crc: u32,
+    out_table: [u32; 256],
+    zeros_crc: u32,
+    padding_table: Vec<[u32; 256]>, // appending the padding, one table per byte of the CRC
+    padding_crc: u32,
+    targets: HashMap<u32, Vec<usize>>, // CRC32 of the first slice -> entries
+    filter: Vec<bool>, // by the low 16 bits of the targets, to avoid most lookups
+    outgoing: ByteReader,
+}
+
+impl Window {
+    fn new(file: &str, length: u64, padding: u64) -> Result<Window, io::Error> {
+        let zeros_crc = zeros_crc32(length);
+        let zeros_crc_1 = zeros_crc32(length + 1);
+
+        let mut out_table = [0u32; 256];
+        for (b, value) in out_table.iter_mut().enumerate() {
+            let byte_crc = hash_backend::crc32(&[b as u8]);
+            *value = file_verification::crc32_combine(byte_crc, zeros_crc, length) ^ zeros_crc_1;
+        }
+
+        let mut padding_table: Vec<[u32; 256]> = Vec::new();
+        if padding > 0 {
+            for shift in 0..4 {
+                let mut table = [0u32; 256];
+                for (v, value) in table.iter_mut().enumerate() {
+                    *value = file_verification::crc32_combine((v as u32) << (8 * shift), 0, padding);
+                }
+                padding_table.push(table);
+            }
+        }
+
+        Ok(Window {
+            length,
+            padding,
+            crc: 0,
+            out_table,
+            zeros_crc,
+            padding_table,
+            padding_crc: zeros_crc32(padding),
+            targets: HashMap::new(),
+            filter: vec![false; 1 << 16],
+            outgoing: ByteReader::open(file, false)?,
+        })
+    }
+
+    fn add_target(&mut self, crc: u32, entry: usize) {
+        self.targets.entry(crc).or_default().push(entry);
+        self.filter[(crc & 0xffff) as usize] = true;
+    }
+
+    // CRC32 of the window as PAR2 calculates it for a slice
+    #[inline]
+    fn slice_crc32(&self) -> u32 {
+        let crc = self.crc ^ self.zeros_crc;
+        if self.padding == 0 {
+            return crc;
+        }
+        let shifted = (0..4).fold(0, |acc, shift| acc ^ self.padding_table[shift][((crc >> (8 * shift)) & 0xff) as usize]);
+        shifted ^ self.padding_crc
+    }
+}
+
+// Looks for the given entries within the file. Only entries having a length and either
+// PAR2 slice checksums or the MD5 of their first 16k can be found.
+pub fn find_entries(file: &str, file_length: u64, entries: &[&ChecksumEntry]) -> Result<Vec<Piece>, io::Error> {
+    let mut found: Vec<Piece> = Vec::new();
+    let mut open: Vec<bool> = entries.iter()
+        .map(|e| e.has_digests() && e.length.is_some_and(|l| l > 0 && l <= file_length))
+        .collect();
+
+    find_by_slices(file, file_length, entries, &mut open, &mut found)?;
+    find_by_first_16k(file, file_length, entries, &mut open, &mut found)?;
+
+    found.sort_by_key(|p| (p.offset, p.entry));
+    Ok(found)
+}
+
+fn find_by_slices(file: &str, file_length: u64, entries: &[&ChecksumEntry], open: &mut [bool], found: &mut Vec<Piece>) -> Result<(), io::Error> {
+    let mut windows: BTreeMap<(u64, u64), Window> = BTreeMap::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let slice_size = match entry.slice_size {
+            Some(v) if v > 0 => v,
+            _ => continue,
+        };
+        if !open[i] || entry.slice_checksums.is_empty() {
+            continue;
+        }
+        let length = entry.length.unwrap().min(slice_size);
+        let key = (length, slice_size - length);
+        let window = match windows.entry(key) {
+            Entry::Vacant(v) => v.insert(Window::new(file, key.0, key.1)?),
+            Entry::Occupied(o) => o.into_mut(),
+        };
+        window.add_target(entry.slice_checksums[0].crc32, i);
+    }
+    let mut windows: Vec<Window> = windows.into_values().collect();
+    if windows.is_empty() {
+        return Ok(());
+    }
+
+    let table = crc32_table();
+    let mut incoming = ByteReader::open(file, true)?;
+    let mut position: u64 = 0;
+
+    while let Some(b) = incoming.next()? {
+        position += 1;
+
+        let mut any_found = false;
+        for window in windows.iter_mut() {
+            window.crc = table[((window.crc ^ b as u32) & 0xff) as usize] ^ (window.crc >> 8);
+            if position > window.length {
+                if let Some(out) = window.outgoing.next()? {
+                    window.crc ^= window.out_table[out as usize];
+                }
+            }
+            if position < window.length || window.targets.is_empty() {
+                continue;
+            }
+
+            let crc = window.slice_crc32();
+            if !window.filter[(crc & 0xffff) as usize] {
+                continue;
+            }
+
+            let offset = position - window.length;
+            let candidates = match window.targets.get(&crc) {
+                Some(v) => v.clone(),
+                None => continue,
+            };
+            let mut ranges: HashMap<(u64, Vec<HashAlgorithm>), ChecksumEntry> = HashMap::new();
+            for index in candidates {
+                if !open[index] || offset + entries[index].length.unwrap() > file_length {
+                    continue;
+                }
+                if verify_entry(file, offset, entries[index], &mut ranges)? {
+                    open[index] = false;
+                    found.push(Piece { entry: index, offset, length: entries[index].length.unwrap() });
+                    any_found = true;
+                }
+            }
+            let targets = &mut window.targets;
+            for list in targets.values_mut() {
+                list.retain(|i| open[*i]);
+            }
+            targets.retain(|_, list| !list.is_empty());
+        }
+
+        if any_found && windows.iter().all(|w| w.targets.is_empty()) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Files in disk images and tar archives start at sector boundaries
+fn find_by_first_16k(file: &str, file_length: u64, entries: &[&ChecksumEntry], open: &mut [bool], found: &mut Vec<Piece>) -> Result<(), io::Error> {
+    let mut targets: BTreeMap<u64, Vec<usize>> = BTreeMap::new(); // length of the prefix -> entries
+    for (i, entry) in entries.iter().enumerate() {
+        if open[i] && entry.first_16k_md5.is_some() && entry.slice_checksums.is_empty() {
+            let prefix = entry.length.unwrap().min(file_verification::FIRST_16K);
+            targets.entry(prefix).or_default().push(i);
+        }
+    }
+    if targets.is_empty() {
+        return Ok(());
+    }
+
+    let mut reader = SectorReader::open(file)?;
+    let mut offset: u64 = 0;
+
+    while offset < file_length && targets.values().any(|list| list.iter().any(|i| open[*i])) {
+        let buffer = reader.read_at(offset, file_verification::FIRST_16K as usize)?;
+        let available = buffer.len();
+
+        let mut ranges: HashMap<(u64, Vec<HashAlgorithm>), ChecksumEntry> = HashMap::new();
+        for (prefix, list) in targets.iter() {
+            if *prefix as usize > available || !list.iter().any(|i| open[*i]) {
+                continue;
+            }
+            let md5 = md5_of(&buffer[..*prefix as usize]);
+            for index in list.iter().cloned() {
+                let entry = entries[index];
+                if !open[index] || entry.first_16k_md5.unwrap() != md5 || offset + entry.length.unwrap() > file_length {
+                    continue;
+                }
+                if verify_entry(file, offset, entry, &mut ranges)? {
+                    open[index] = false;
+                    found.push(Piece { entry: index, offset, length: entry.length.unwrap() });
+                }
+            }
+        }
+        offset += SECTOR_SIZE;
+    }
+    Ok(())
+}
+
+fn md5_of(data: &[u8]) -> [u8; 16] {
+    let mut hasher = Hasher::new(HashAlgorithm::Md5);
+    hasher.update(data);
+    let mut md5 = [0u8; 16];
+    md5.copy_from_slice(&hasher.finish());
+    md5
+}
+
+// Checks the first slice before hashing the whole range. Entries sharing the length
+// at one offset are hashed only once.
+fn verify_entry(file: &str, offset: u64, entry: &ChecksumEntry, ranges: &mut HashMap<(u64, Vec<HashAlgorithm>), ChecksumEntry>) -> Result<bool, io::Error> {
+    let length = entry.length.unwrap();
+
+    if let (Some(slice_size), Some(first_slice)) = (entry.slice_size, entry.slice_checksums.first()) {
+        let mut f = File::open(file)?;
+        f.seek(SeekFrom::Start(offset))?;
+        let checksum = file_verification::read_slice_checksum(&mut f.take(length.min(slice_size)), slice_size)?;
+        if checksum.is_none_or(|c| c.0.md5 != first_slice.md5) {
+            return Ok(false);
+        }
+    }
+
+    let algorithms: Vec<HashAlgorithm> = entry.digests.keys().cloned().collect();
+    let key = (length, algorithms);
+    if !ranges.contains_key(&key) {
+        let range = ChecksumEntry {
+            digests: file_verification::get_checksums_of_range(file, offset, length, &key.1)?,
+            ..Default::default()
+        };
+        ranges.insert(key.clone(), range);
+    }
+    Ok(entry.matches_digests(&ranges[&key]))
+}
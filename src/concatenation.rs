@@ -0,0 +1,471 @@
+// reverse-checksum-renamer
+//
+// Copyright (C) 2020  Martin Feil aka. SGDW
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>
+
+// Files which are concatenations of catalog entries, e.g. 'file.001' to 'file.010'
+// joined into one file, and the reverse: several files which are pieces of one entry.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use file_verification;
+use file_verification::{ChecksumEntry, HashAlgorithm, Hasher};
+use rename_journal;
+use throttle;
+
+// Limits of the search for pieces, the number of orderings grows factorially
+const MAX_JOIN_STEPS: usize = 100_000;
+const MAX_JOIN_VERIFICATIONS: usize = 16;
+
+// Part of a file matching a catalog entry
+#[derive(Clone, Debug)]
+pub struct Piece {
+    pub entry: usize, // index into the given entries
+    pub offset: u64,
+    pub length: u64,
+}
+
+struct Search<'a> {
+    file: &'a str,
+    file_length: u64,
+    entries: &'a [&'a ChecksumEntry],
+    used: Vec<bool>,
+    digests: HashMap<(u64, u64), ChecksumEntry>, // ranges hashed so far
+    dead_ends: HashSet<(u64, Vec<bool>)>, // offsets no sequence of the unused entries continues from
+}
+
+// Looks for an ordered sequence of at least two entries which makes up the whole file.
+// Only ranges starting at a boundary and having the length of an unused entry are hashed.
+pub fn find_concatenation(file: &str, file_length: u64, entries: &[&ChecksumEntry]) -> Result<Option<Vec<Piece>>, io::Error> {
+    let usable = entries.iter().filter(|e| e.length.is_some() && e.has_digests()).count();
+    if usable < 2 {
+        return Ok(None);
+    }
+
+    let mut search = Search {
+        file,
+        file_length,
+        entries,
+        used: vec![false; entries.len()],
+        digests: HashMap::new(),
+        dead_ends: HashSet::new(),
+    };
+
+    match search.find_from(0)? {
+        Some(pieces) if pieces.len() >= 2 => Ok(Some(pieces)),
+        _ => Ok(None),
+    }
+}
+
+impl<'a> Search<'a> {
+    fn find_from(&mut self, offset: u64) -> Result<Option<Vec<Piece>>, io::Error> {
+        if offset == self.file_length {
+            return Ok(Some(Vec::new()));
+        }
+        if self.dead_ends.contains(&(offset, self.used.clone())) {
+            return Ok(None);
+        }
+
+        // Longest candidates first, a few large entries are quicker to verify
+        let lengths: BTreeSet<u64> = self.entries.iter().enumerate()
+            .filter(|&(i, _)| !self.used[i])
+            .filter_map(|(_, e)| e.length)
+            .filter(|l| *l > 0 && offset + *l <= self.file_length)
+            .collect();
+
+        for length in lengths.into_iter().rev() {
+            for index in self.get_matching_entries(offset, length)? {
+                self.used[index] = true;
+                if let Some(mut pieces) = self.find_from(offset + length)? {
+                    pieces.insert(0, Piece { entry: index, offset, length });
+                    return Ok(Some(pieces));
+                }
+                self.used[index] = false;
+            }
+        }
+
+        self.dead_ends.insert((offset, self.used.clone()));
+        Ok(None)
+    }
+
+    fn get_matching_entries(&mut self, offset: u64, length: u64) -> Result<Vec<usize>, io::Error> {
+        let candidates: Vec<usize> = (0..self.entries.len())
+            .filter(|i| !self.used[*i] && self.entries[*i].length == Some(length) && self.entries[*i].has_digests())
+            .collect();
+        if candidates.is_empty() {
+            return Ok(candidates);
+        }
+
+        if !self.digests.contains_key(&(offset, length)) {
+            let algorithms: BTreeSet<HashAlgorithm> = candidates.iter()
+                .flat_map(|i| self.entries[*i].digests.keys().cloned())
+                .collect();
+            let algorithms: Vec<HashAlgorithm> = algorithms.into_iter().collect();
+
+            let mut range = ChecksumEntry::from_path(&self.file.to_string(), length);
+            range.digests = file_verification::get_checksums_of_range(self.file, offset, length, &algorithms)?;
+            self.digests.insert((offset, length), range);
+        }
+
+        let range = &self.digests[&(offset, length)];
+        Ok(candidates.into_iter().filter(|i| self.entries[*i].matches_digests(range)).collect())
+    }
+}
+
+// Copies each piece of the file into its own file
+pub fn split_file(file: &str, pieces: &[(u64, u64, &Path)]) -> Result<(), io::Error> {
+    let mut source = File::open(file)?;
+    for &(offset, length, target) in pieces {
+        source.seek(SeekFrom::Start(offset))?;
+        write_file(target, |target_file| {
+            let copied = io::copy(&mut (&source).take(length), target_file)?;
+            if copied < length {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file is shorter than expected"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+
+// A file which might be a piece of a catalog entry
+pub struct Input<'a> {
+    pub path: &'a str,
+    pub length: u64,
+    pub crc32: Option<u32>,
+}
+
+struct JoinSearch<'a> {
+    entry: &'a ChecksumEntry,
+    entry_length: Option<u64>,
+    inputs: &'a [Input<'a>],
+    used: Vec<bool>,
+    order: Vec<usize>,
+    slice_checks: HashMap<(usize, u64), bool>, // (input, offset) -> slices match
+    steps: usize,
+    verifications: usize,
+}
+
+// Looks for an ordering of at least two inputs whose concatenation is the entry.
+// The search is pruned by the sizes and, for PAR2 entries, by the slice checksums
+// and the MD5 of the first 16k at the position of each piece. Entries without a size
+// (plain SFV) are only compared by combining the CRC32 of the inputs.
+pub fn find_pieces(entry: &ChecksumEntry, inputs: &[Input]) -> Result<Option<Vec<usize>>, io::Error> {
+    if !entry.has_digests() || entry.length == Some(0) {
+        return Ok(None);
+    }
+    if entry.length.is_none() && entry.get_crc32().is_none() {
+        return Ok(None);
+    }
+    let entry_length = entry.length;
+
+    let mut search = JoinSearch {
+        entry,
+        entry_length,
+        inputs,
+        used: vec![false; inputs.len()],
+        order: Vec::new(),
+        slice_checks: HashMap::new(),
+        steps: 0,
+        verifications: 0,
+    };
+
+    if search.find_from(0)? {
+        Ok(Some(search.order))
+    } else {
+        Ok(None)
+    }
+}
+
+impl<'a> JoinSearch<'a> {
+    fn find_from(&mut self, offset: u64) -> Result<bool, io::Error> {
+        let entry_length = match self.entry_length {
+            Some(v) => v,
+            None => {
+                if self.order.len() >= 2 && self.verify_crc32() {
+                    return Ok(true);
+                }
+                u64::MAX
+            }
+        };
+        if offset == entry_length {
+            return if self.order.len() >= 2 { self.verify() } else { Ok(false) };
+        }
+
+        let remaining: u64 = (0..self.inputs.len())
+            .filter(|i| !self.used[*i])
+            .map(|i| self.inputs[i].length)
+            .sum();
+        if self.entry_length.is_some() && remaining < entry_length - offset {
+            return Ok(false);
+        }
+
+        for index in 0..self.inputs.len() {
+            self.steps += 1;
+            if self.steps > MAX_JOIN_STEPS || self.verifications >= MAX_JOIN_VERIFICATIONS {
+                return Ok(false);
+            }
+
+            let length = self.inputs[index].length;
+            if self.used[index] || length == 0 || offset.saturating_add(length) > entry_length {
+                continue;
+            }
+            if self.entry_length.is_none() && self.inputs[index].crc32.is_none() {
+                continue;
+            }
+            if !self.check_slices(index, offset)? {
+                continue;
+            }
+
+            self.used[index] = true;
+            self.order.push(index);
+            if self.find_from(offset + length)? {
+                return Ok(true);
+            }
+            self.order.pop();
+            self.used[index] = false;
+        }
+        Ok(false)
+    }
+
+    // Compares the first slice lying completely within the piece and the first 16k
+    fn check_slices(&mut self, index: usize, offset: u64) -> Result<bool, io::Error> {
+        if let Some(v) = self.slice_checks.get(&(index, offset)) {
+            return Ok(*v);
+        }
+
+        let input = &self.inputs[index];
+        let mut matches = true;
+
+        if offset == 0 && input.length >= file_verification::FIRST_16K {
+            if let Some(first_16k_md5) = self.entry.first_16k_md5 {
+                let digests = file_verification::get_checksums_of_range(input.path, 0, file_verification::FIRST_16K, &[HashAlgorithm::Md5])?;
+                matches = digests[&HashAlgorithm::Md5][..] == first_16k_md5[..];
+            }
+        }
+
+        if let (Some(slice_size), Some(entry_length)) = (self.entry.slice_size.filter(|v| *v > 0), self.entry_length) {
+            let mut slice = offset / slice_size;
+            if slice * slice_size < offset {
+                slice += 1;
+            }
+            let start = slice * slice_size;
+            let end = (start + slice_size).min(entry_length);
+
+            if matches && end <= offset + input.length && (slice as usize) < self.entry.slice_checksums.len() {
+                let mut f = File::open(input.path)?;
+                f.seek(SeekFrom::Start(start - offset))?;
+                let checksum = file_verification::read_slice_checksum(&mut f.take(end - start), slice_size)?;
+                matches = checksum.is_some_and(|c| c.0.md5 == self.entry.slice_checksums[slice as usize].md5);
+            }
+        }
+
+        self.slice_checks.insert((index, offset), matches);
+        Ok(matches)
+    }
+
+    // CRC32 of the current ordering from the CRC32 of the pieces, without reading them
+    fn verify_crc32(&self) -> bool {
+        if self.order.iter().any(|i| self.inputs[*i].crc32.is_none()) {
+            return false;
+        }
+        let crc = self.order.iter()
+            .map(|i| &self.inputs[*i])
+            .fold(0, |crc, input| file_verification::crc32_combine(crc, input.crc32.unwrap(), input.length));
+        self.entry.get_crc32() == Some(crc)
+    }
+
+    // CRC32 is combined if possible, everything else has to be hashed in order
+    fn verify(&mut self) -> Result<bool, io::Error> {
+        let strong: Vec<HashAlgorithm> = self.entry.digests.keys().cloned().filter(|a| a.is_strong()).collect();
+        if strong.is_empty() && self.order.iter().all(|i| self.inputs[*i].crc32.is_some()) {
+            return Ok(self.verify_crc32());
+        }
+
+        self.verifications += 1;
+        let algorithms: Vec<HashAlgorithm> = if strong.is_empty() { vec![HashAlgorithm::Crc32] } else { strong };
+        let mut hashers: Vec<Hasher> = algorithms.iter().map(|a| Hasher::new(*a)).collect();
+        let mut buffer = vec![0u8; 1024*1024];
+        for index in &self.order {
+            let mut f = File::open(self.inputs[*index].path)?;
+            loop {
+                let read_count = f.read(&mut buffer)?;
+                if read_count == 0 { break; }
+                throttle::consume(read_count);
+                for hasher in hashers.iter_mut() {
+                    hasher.update(&buffer[..read_count]);
+                }
+            }
+        }
+
+        let mut joined = ChecksumEntry::default();
+        for hasher in hashers {
+            joined.set_digest(hasher.algorithm(), hasher.finish());
+        }
+        Ok(self.entry.matches_digests(&joined))
+    }
+}
+
+// Writes the pieces one after the other into the target file
+pub fn join_files(pieces: &[&str], target: &Path) -> Result<(), io::Error> {
+    write_file(target, |target_file| {
+        for piece in pieces {
+            let mut source = File::open(piece)?;
+            io::copy(&mut source, target_file)?;
+        }
+        Ok(())
+    })
+}
+
+// Written to a temporary name next to the target, which is renamed when complete, so a
+// failed or interrupted write never leaves a truncated file under the catalog name
+fn write_file<F>(target: &Path, write: F) -> Result<(), io::Error>
+        where F: FnOnce(&mut File) -> Result<(), io::Error> {
+    let temporary = rename_journal::temporary_path(target);
+    let result = File::create(&temporary)
+        .and_then(|mut file| {
+            write(&mut file)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temporary, target));
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hash_backend;
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rcr-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn test_data(len: usize, seed: usize) -> Vec<u8> {
+        (0..len).map(|i| ((i + seed) * 37 % 251) as u8).collect()
+    }
+
+    fn entry_of(data: &[u8], algorithm: HashAlgorithm, with_length: bool) -> ChecksumEntry {
+        let mut hasher = Hasher::new(algorithm);
+        hasher.update(data);
+        let mut entry = ChecksumEntry {
+            valid: true,
+            length: if with_length { Some(data.len() as u64) } else { None },
+            ..Default::default()
+        };
+        entry.set_digest(algorithm, hasher.finish());
+        entry
+    }
+
+    #[test]
+    fn finds_two_concatenated_entries() {
+        let dir = test_dir("concatenation");
+        let (a, b, c) = (test_data(5000, 1), test_data(3000, 2), test_data(3000, 3));
+        let file = dir.join("joined");
+        fs::write(&file, [&a[..], &b[..]].concat()).unwrap();
+        let file = file.to_str().unwrap();
+
+        // c has the length of b, but not its content
+        let entries = [entry_of(&c, HashAlgorithm::Md5, true), entry_of(&a, HashAlgorithm::Md5, true), entry_of(&b, HashAlgorithm::Md5, true)];
+        let entries: Vec<&ChecksumEntry> = entries.iter().collect();
+        let pieces = find_concatenation(file, 8000, &entries).unwrap().unwrap();
+        let pieces: Vec<(usize, u64, u64)> = pieces.iter().map(|p| (p.entry, p.offset, p.length)).collect();
+        assert_eq!(pieces, vec![(1, 0, 5000), (2, 5000, 3000)]);
+
+        // A single entry is not a concatenation
+        assert!(find_concatenation(file, 8000, &entries[..2]).unwrap().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn joins_by_combined_crc32_without_reading() {
+        let (a, b, c) = (test_data(700, 1), test_data(301, 2), test_data(5, 3));
+        let inputs = [
+            Input { path: "/nonexistent/c", length: 5, crc32: Some(hash_backend::crc32(&c)) },
+            Input { path: "/nonexistent/b", length: 301, crc32: Some(hash_backend::crc32(&b)) },
+            Input { path: "/nonexistent/a", length: 700, crc32: Some(hash_backend::crc32(&a)) },
+        ];
+        let joined = [&a[..], &b[..]].concat();
+
+        // SFV entries without and with a size
+        let entry = entry_of(&joined, HashAlgorithm::Crc32, false);
+        assert_eq!(find_pieces(&entry, &inputs).unwrap(), Some(vec![2, 1]));
+        let entry = entry_of(&joined, HashAlgorithm::Crc32, true);
+        assert_eq!(find_pieces(&entry, &inputs).unwrap(), Some(vec![2, 1]));
+
+        let entry = entry_of(&test_data(1001, 4), HashAlgorithm::Crc32, true);
+        assert_eq!(find_pieces(&entry, &inputs).unwrap(), None);
+    }
+
+    #[test]
+    fn stops_after_too_many_verifications() {
+        let dir = test_dir("verifications");
+        let mut paths: Vec<String> = Vec::new();
+        for i in 0..4 {
+            let path = dir.join(format!("piece{}", i));
+            fs::write(&path, test_data(10, i)).unwrap();
+            paths.push(path.to_str().unwrap().to_string());
+        }
+        let inputs: Vec<Input> = paths.iter().map(|p| Input { path: p, length: 10, crc32: None }).collect();
+
+        // Every ordering has the size of the entry, but none its MD5
+        let entry = entry_of(&test_data(40, 9), HashAlgorithm::Md5, true);
+        let mut search = JoinSearch {
+            entry: &entry,
+            entry_length: entry.length,
+            inputs: &inputs,
+            used: vec![false; inputs.len()],
+            order: Vec::new(),
+            slice_checks: HashMap::new(),
+            steps: 0,
+            verifications: 0,
+        };
+        assert!(!search.find_from(0).unwrap());
+        assert_eq!(search.verifications, MAX_JOIN_VERIFICATIONS);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stops_after_too_many_steps() {
+        // Without a size every ordering of the inputs would be tried
+        let inputs: Vec<Input> = (0..10).map(|i| Input { path: "/nonexistent", length: 10 + i, crc32: Some(i as u32) }).collect();
+        let entry = entry_of(&test_data(100, 0), HashAlgorithm::Crc32, false);
+        let mut search = JoinSearch {
+            entry: &entry,
+            entry_length: None,
+            inputs: &inputs,
+            used: vec![false; inputs.len()],
+            order: Vec::new(),
+            slice_checks: HashMap::new(),
+            steps: 0,
+            verifications: 0,
+        };
+        assert!(!search.find_from(0).unwrap());
+        assert!(search.steps > MAX_JOIN_STEPS);
+    }
+}
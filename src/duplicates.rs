@@ -0,0 +1,102 @@
+// reverse-checksum-renamer
+//
+// Copyright (C) 2020  Martin Feil aka. SGDW
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>
+
+// Catalog entries having the same content, e.g. files shared by the discs of a set.
+// One file is renamed to the first entry, the others are created from it.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use rename_journal;
+
+#[cfg(target_os = "linux")]
+extern crate libc;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DuplicateMode {
+    Copy,
+    Hardlink,
+    Reflink, // shares the data blocks until changed, e.g. on Btrfs or XFS
+}
+
+impl DuplicateMode {
+    pub fn from_name(name: &str) -> Option<DuplicateMode> {
+        match name.to_lowercase().as_str() {
+            "copy" => Some(DuplicateMode::Copy),
+            "hardlink" => Some(DuplicateMode::Hardlink),
+            "reflink" => Some(DuplicateMode::Reflink),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DuplicateMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DuplicateMode::Copy => "copy",
+            DuplicateMode::Hardlink => "hardlink",
+            DuplicateMode::Reflink => "reflink",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub fn create_duplicate(source: &Path, target: &Path, mode: DuplicateMode) -> Result<(), io::Error> {
+    match mode {
+        DuplicateMode::Copy => copy(source, target),
+        DuplicateMode::Hardlink => fs::hard_link(source, target),
+        DuplicateMode::Reflink => reflink(source, target),
+    }
+}
+
+// Copied to a temporary name first, an interrupted copy does not leave a truncated
+// file having the name of the entry
+fn copy(source: &Path, target: &Path) -> Result<(), io::Error> {
+    let temporary = rename_journal::temporary_path(target);
+    let result = fs::copy(source, &temporary)
+        .and_then(|_| fs::OpenOptions::new().write(true).open(&temporary)?.sync_all())
+        .and_then(|_| fs::rename(&temporary, target));
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(source: &Path, target: &Path) -> Result<(), io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    const FICLONE: libc::c_ulong = 0x4004_9409;
+
+    let source_file = fs::File::open(source)?;
+    let target_file = fs::OpenOptions::new().write(true).create_new(true).open(target)?;
+    if unsafe { libc::ioctl(target_file.as_raw_fd(), FICLONE as _, source_file.as_raw_fd()) } != 0 {
+        // Only the empty file created above is removed
+        let error = io::Error::last_os_error();
+        drop(target_file);
+        let _ = fs::remove_file(target);
+        return Err(error);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_source: &Path, _target: &Path) -> Result<(), io::Error> {
+    Err(io::Error::new(io::ErrorKind::Other, "reflinks are not supported on this platform"))
+}
@@ -21,6 +21,7 @@ use std;
 use std::fmt;
 
 use std::io::Read;
+use std::io::{Seek, SeekFrom};
 use std::io::Write;
 
 use std::fs;
@@ -28,11 +29,23 @@ use std::fs::File;
 
 use std::path::{Path}; // , PathBuf};
 
+use std::collections::{BTreeMap, BTreeSet};
+
 extern crate crc;
 use self::crc::{crc32, Hasher32}; // https://docs.rs/crc/1.7.0/crc/index.html
 
 extern crate md5;
 
+extern crate crc32fast;
+extern crate md_5;
+
+extern crate sha1;
+extern crate sha2;
+use self::sha2::Digest;
+
+use super::utils;
+use super::hash_backend;
+use super::throttle;
 use super::par2_reader;
 use super::sfv_reader;
 
@@ -52,6 +65,211 @@ impl fmt::Display for SourceTypes {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum HashAlgorithm {
+    Crc32,
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+pub const ALL_ALGORITHMS: &[HashAlgorithm] = &[
+    HashAlgorithm::Crc32, HashAlgorithm::Md5, HashAlgorithm::Sha1, HashAlgorithm::Sha256, HashAlgorithm::Sha512];
+
+impl HashAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HashAlgorithm::Crc32 => "crc32",
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<HashAlgorithm> {
+        ALL_ALGORITHMS.iter().cloned().find(|a| a.name() == name.to_lowercase())
+    }
+
+    // Length of a digest in bytes
+    pub fn digest_len(&self) -> usize {
+        match *self {
+            HashAlgorithm::Crc32 => 4,
+            HashAlgorithm::Md5 => 16,
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Sha512 => 64,
+        }
+    }
+
+    // A match on a strong digest is trusted, CRC32 is only good enough to detect errors
+    pub fn is_strong(&self) -> bool {
+        *self != HashAlgorithm::Crc32
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name().to_uppercase())
+    }
+}
+
+// Incremental hashing of one algorithm with the backend chosen in hash_backend
+pub enum Hasher {
+    Crc32(Box<crc32::Digest>), // holds its lookup table
+    Crc32Fast(crc32fast::Hasher),
+    Md5(md5::Context),
+    Md5RustCrypto(md_5::Md5),
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Hasher {
+        match algorithm {
+            HashAlgorithm::Crc32 => match hash_backend::crc32_backend() {
+                hash_backend::Crc32Backend::Crc32Fast => Hasher::Crc32Fast(crc32fast::Hasher::new()),
+                hash_backend::Crc32Backend::Table => Hasher::Crc32(Box::new(crc32::Digest::new(crc32::IEEE))),
+            },
+            HashAlgorithm::Md5 => match hash_backend::md5_backend() {
+                hash_backend::Md5Backend::RustCrypto => Hasher::Md5RustCrypto(md_5::Md5::new()),
+                hash_backend::Md5Backend::Md5 => Hasher::Md5(md5::Context::new()),
+            },
+            HashAlgorithm::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            HashAlgorithm::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
+        }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        match *self {
+            Hasher::Crc32(_) | Hasher::Crc32Fast(_) => HashAlgorithm::Crc32,
+            Hasher::Md5(_) | Hasher::Md5RustCrypto(_) => HashAlgorithm::Md5,
+            Hasher::Sha1(_) => HashAlgorithm::Sha1,
+            Hasher::Sha256(_) => HashAlgorithm::Sha256,
+            Hasher::Sha512(_) => HashAlgorithm::Sha512,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match *self {
+            Hasher::Crc32(ref mut h) => h.write(data),
+            Hasher::Crc32Fast(ref mut h) => h.update(data),
+            Hasher::Md5(ref mut h) => h.consume(data),
+            Hasher::Md5RustCrypto(ref mut h) => h.update(data),
+            Hasher::Sha1(ref mut h) => h.update(data),
+            Hasher::Sha256(ref mut h) => h.update(data),
+            Hasher::Sha512(ref mut h) => h.update(data),
+        }
+    }
+
+    // CRC32 is returned big endian, so its hex string is the usual SFV notation
+    pub fn finish(self) -> Vec<u8> {
+        match self {
+            Hasher::Crc32(h) => h.sum32().to_be_bytes().to_vec(),
+            Hasher::Crc32Fast(h) => h.finalize().to_be_bytes().to_vec(),
+            Hasher::Md5(h) => h.compute().0.to_vec(),
+            Hasher::Md5RustCrypto(h) => h.finalize().to_vec(),
+            Hasher::Sha1(h) => h.finalize().to_vec(),
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+            Hasher::Sha512(h) => h.finalize().to_vec(),
+        }
+    }
+}
+
+fn gf2_matrix_times(matrix: &[u32; 32], mut vector: u32) -> u32 {
+    let mut sum = 0;
+    for row in matrix.iter() {
+        if vector == 0 { break; }
+        if vector & 1 != 0 { sum ^= *row; }
+        vector >>= 1;
+    }
+    sum
+}
+
+fn gf2_matrix_square(square: &mut [u32; 32], matrix: &[u32; 32]) {
+    for (row, value) in square.iter_mut().zip(matrix.iter()) {
+        *row = gf2_matrix_times(matrix, *value);
+    }
+}
+
+// CRC32 of the concatenation of two blocks from their CRC32s and the length of the
+// second block (as crc32_combine() of zlib), so the blocks can be hashed in parallel
+pub fn crc32_combine(mut crc1: u32, crc2: u32, mut len2: u64) -> u32 {
+    if len2 == 0 {
+        return crc1;
+    }
+
+    let mut even = [0u32; 32];
+    let mut odd = [0u32; 32];
+
+    odd[0] = 0xedb8_8320; // reversed polynomial
+    let mut row = 1;
+    for value in odd.iter_mut().skip(1) {
+        *value = row;
+        row <<= 1;
+    }
+    gf2_matrix_square(&mut even, &odd); // 2 zero bits
+    gf2_matrix_square(&mut odd, &even); // 4 zero bits
+
+    // Apply len2 zero bytes to crc1
+    loop {
+        gf2_matrix_square(&mut even, &odd);
+        if len2 & 1 != 0 { crc1 = gf2_matrix_times(&even, crc1); }
+        len2 >>= 1;
+        if len2 == 0 { break; }
+
+        gf2_matrix_square(&mut odd, &even);
+        if len2 & 1 != 0 { crc1 = gf2_matrix_times(&odd, crc1); }
+        len2 >>= 1;
+        if len2 == 0 { break; }
+    }
+    crc1 ^ crc2
+}
+
+// Errors of the catalog parsers. Catalog files often come with untrusted downloads,
+// so malformed input has to end up here instead of a panic.
+#[derive(Debug)]
+pub enum ParseError {
+    Io(std::io::Error),
+    Truncated { offset: u64 },
+    InvalidPacketLength { offset: u64, length: u64 },
+    PacketTooLarge { offset: u64, length: u64 },
+    InvalidPacket { offset: u64, reason: &'static str },
+    LineTooLong { line: usize },
+    InvalidEncoding { line: usize },
+    Decode(utils::DecodeError),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Io(ref e) => write!(f, "{}", e),
+            ParseError::Truncated { offset } => write!(f, "file is truncated at offset {}", offset),
+            ParseError::InvalidPacketLength { offset, length } => write!(f, "invalid packet length {} at offset {}", length, offset),
+            ParseError::PacketTooLarge { offset, length } => write!(f, "packet of {} bytes at offset {} is too large", length, offset),
+            ParseError::InvalidPacket { offset, reason } => write!(f, "invalid packet at offset {}: {}", offset, reason),
+            ParseError::LineTooLong { line } => write!(f, "line {} is too long", line),
+            ParseError::InvalidEncoding { line } => write!(f, "line {} is not valid UTF-8", line),
+            ParseError::Decode(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<utils::DecodeError> for ParseError {
+    fn from(e: utils::DecodeError) -> ParseError {
+        ParseError::Decode(e)
+    }
+}
+
+impl From<std::io::Error> for ParseError {
+    fn from(e: std::io::Error) -> ParseError {
+        ParseError::Io(e)
+    }
+}
+
 pub struct ChecksumCatalog {
     pub entries: Vec<ChecksumEntry>,
     pub valid: bool,
@@ -59,6 +277,26 @@ pub struct ChecksumCatalog {
     pub source_type: SourceTypes,
     pub source_file: String,
     pub state: u64,
+    pub recovery_set_id: Option<[u8; 16]>,
+    pub volume_files: Vec<String>, // further files (PAR2 volumes) the catalog was merged from
+    pub slice_size: Option<u64>,
+    pub recovery_exponents: Vec<u32>, // one per available PAR2 recovery block
+    pub warnings: Vec<String>, // damage the catalog was read despite of, e.g. a truncated PAR2 volume
+}
+
+impl ChecksumCatalog {
+    pub fn get_source_files(&self) -> Vec<&String> {
+        let mut files = vec![&self.source_file];
+        files.extend(self.volume_files.iter());
+        files
+    }
+
+    // Algorithms of the digests of all entries, e.g. CRC32 for SFV or MD5 for PAR2
+    pub fn get_algorithms(&self) -> BTreeSet<HashAlgorithm> {
+        self.entries.iter()
+            .flat_map(|e| e.digests.keys().cloned())
+            .collect()
+    }
 }
 
 impl fmt::Debug for ChecksumCatalog {
@@ -70,22 +308,158 @@ impl fmt::Debug for ChecksumCatalog {
     }
 }
 
+#[derive(Default)]
 pub struct ChecksumEntry {
     pub filename: String,
     pub path: String,
-    pub checksum_crc32: Option<u32>,
-    pub checksum_md5: Option<[u8; 16]>,
+    pub digests: BTreeMap<HashAlgorithm, Vec<u8>>,
     pub valid: bool,
     pub state: u64,
+    pub file_id: Option<[u8; 16]>,
+    pub length: Option<u64>,
+    pub first_16k_md5: Option<[u8; 16]>,
+    pub slice_size: Option<u64>,
+    pub slice_checksums: Vec<SliceChecksum>,
+    pub bad_slices: Option<usize>,
+}
+
+// MD5 and CRC32 of one slice of a file as found in PAR2 IFSC packets,
+// the last slice is padded with zeros to the slice size
+#[derive(Clone, Copy, PartialEq)]
+pub struct SliceChecksum {
+    pub md5: [u8; 16],
+    pub crc32: u32,
+}
+
+impl fmt::Debug for SliceChecksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SliceChecksum(md5:{}|crc32:{:08x})",
+            utils::byte_array_to_hex(&self.md5),
+            self.crc32,
+        )
+    }
+}
+
+// How a file was matched to a catalog entry, from the most to the least reliable
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum MatchConfidence {
+    StrongHash,      // MD5, SHA1, SHA256 or SHA512
+    Crc32AndSize,
+    First16kAndSize, // --trust-16k
+    Slices,          // most PAR2 slices of a damaged file
+    NameAndSize,     // --trust-names
+    Crc32Only,       // SFV without sizes, collisions are likely with many files
+    NameOnly,        // --trust-names with SFV entries without sizes
+}
+
+impl MatchConfidence {
+    pub fn is_weak(&self) -> bool {
+        *self == MatchConfidence::Crc32Only || *self == MatchConfidence::NameOnly
+    }
+}
+
+impl fmt::Display for MatchConfidence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            MatchConfidence::StrongHash => "strong hash",
+            MatchConfidence::Crc32AndSize => "CRC32 and size",
+            MatchConfidence::First16kAndSize => "first 16k and size",
+            MatchConfidence::Slices => "PAR2 slices",
+            MatchConfidence::NameAndSize => "name and size",
+            MatchConfidence::Crc32Only => "CRC32 only",
+            MatchConfidence::NameOnly => "name only",
+        };
+        write!(f, "{}", name)
+    }
 }
 
 impl ChecksumEntry {
-    pub fn checksum_md5_as_str(&self) -> String {
-        let mut s = String::new();
-        for &byte in self.checksum_md5.unwrap().iter() {
-            s += &format!("{:02x}", byte);
+    pub fn from_path(file: &String, length: u64) -> ChecksumEntry {
+        let file_path = Path::new(file);
+        ChecksumEntry {
+            filename: String::from(file_path.file_name().unwrap().to_str().unwrap()),
+            path: String::from(file_path.to_str().unwrap()),
+            valid: true,
+            length: Some(length),
+            ..Default::default()
+        }
+    }
+
+    pub fn get_digest(&self, algorithm: HashAlgorithm) -> Option<&Vec<u8>> {
+        self.digests.get(&algorithm)
+    }
+
+    pub fn set_digest(&mut self, algorithm: HashAlgorithm, value: Vec<u8>) {
+        self.digests.insert(algorithm, value);
+    }
+
+    pub fn has_digests(&self) -> bool {
+        !self.digests.is_empty()
+    }
+
+    pub fn get_crc32(&self) -> Option<u32> {
+        self.get_digest(HashAlgorithm::Crc32).and_then(|v| utils::read_u32_be(v).ok())
+    }
+
+    pub fn digest_as_str(&self, algorithm: HashAlgorithm) -> String {
+        self.get_digest(algorithm).map_or_else(String::new, |v| utils::byte_array_to_hex(v))
+    }
+
+    // e.g. "crc32:0123abcd md5:..."
+    pub fn digests_as_str(&self) -> String {
+        self.digests.iter()
+            .map(|(algorithm, value)| format!("{}:{}", algorithm.name(), utils::byte_array_to_hex(value)))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    // Entries match on any strong digest both have in common. Only if they share
+    // no strong digest, CRC32 is compared.
+    pub fn matches_digests(&self, other: &ChecksumEntry) -> bool {
+        let shared: Vec<HashAlgorithm> = self.digests.keys()
+            .filter(|a| other.digests.contains_key(a))
+            .cloned()
+            .collect();
+
+        let strong: Vec<HashAlgorithm> = shared.iter().filter(|a| a.is_strong()).cloned().collect();
+        let compared = if strong.is_empty() { shared } else { strong };
+
+        compared.iter().any(|a| self.digests[a] == other.digests[a])
+    }
+
+    // How reliable a match of the digests is. A match of CRC32 alone is rejected if
+    // both sizes are known and differ.
+    pub fn match_confidence(&self, other: &ChecksumEntry) -> Option<MatchConfidence> {
+        if !self.matches_digests(other) {
+            return None;
+        }
+        let strong = self.digests.keys().any(|a| a.is_strong() && other.digests.contains_key(a));
+        if strong {
+            return Some(MatchConfidence::StrongHash);
         }
-        s    
+        match (self.length, other.length) {
+            (Some(a), Some(b)) if a == b => Some(MatchConfidence::Crc32AndSize),
+            (Some(_), Some(_)) => None,
+            _ => Some(MatchConfidence::Crc32Only),
+        }
+    }
+
+    // How reliable a match is that was verified by all digests of this entry, e.g. of
+    // the pieces of a concatenation
+    pub fn verified_confidence(&self) -> MatchConfidence {
+        if self.digests.keys().any(|a| a.is_strong()) {
+            MatchConfidence::StrongHash
+        } else if self.length.is_some() {
+            MatchConfidence::Crc32AndSize
+        } else {
+            MatchConfidence::Crc32Only
+        }
+    }
+
+    // True only if a strong digest shows the content is the same
+    pub fn is_same_content(&self, other: &ChecksumEntry) -> bool {
+        self.digests.keys().any(|a| a.is_strong() && other.digests.contains_key(a))
+            && self.matches_digests(other)
     }
 
     #[allow(dead_code)]
@@ -106,16 +480,26 @@ impl ChecksumEntry {
 
 impl fmt::Debug for ChecksumEntry {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "ChecksumEntry(filename:{:?}|path:{:?}|checksum_crc32:{:?}|checksum_md5:{:?}|valid:{:?})", 
+        write!(f, "ChecksumEntry(filename:{:?}|path:{:?}|digests:{:?}|valid:{:?})", 
             self.filename,
             self.path,
-            self.checksum_crc32,
-            self.checksum_md5_as_str(),
+            self.digests_as_str(),
             self.valid,
         )
     }
 }
 
+// Catalog file names must not point outside of the destination directory
+pub fn is_safe_filename(filename: &str) -> bool {
+    if filename.is_empty() || filename.starts_with('/') || filename.starts_with('\\') {
+        return false;
+    }
+    if filename.len() > 1 && filename.as_bytes()[1] == b':' {
+        return false; // Windows drive letter
+    }
+    !filename.split(['/', '\\']).any(|component| component == "..")
+}
+
 pub fn get_source_type_by_filename(file: &String) -> Option<SourceTypes> {
     let sfv_extension  = ".".to_owned() + sfv_reader::EXTENSION;
     let par2_extension = ".".to_owned() + par2_reader::EXTENSION;
@@ -130,9 +514,9 @@ pub fn get_source_type_by_filename(file: &String) -> Option<SourceTypes> {
     None
 }
 
-pub fn get_checksum_from_file(file: &String, print_progress: bool) -> Result<ChecksumEntry, std::io::Error> {
-    let mut digest_crc32 = crc32::Digest::new(crc32::IEEE);
-    let mut context_md5 = md5::Context::new();
+// Computes the requested digests in one read pass
+pub fn get_checksum_from_file(file: &String, algorithms: &[HashAlgorithm], print_progress: bool) -> Result<ChecksumEntry, std::io::Error> {
+    let mut hashers: Vec<Hasher> = algorithms.iter().map(|a| Hasher::new(*a)).collect();
 
     let mut f = match File::open(file) {
         Ok(v) => v,
@@ -153,9 +537,11 @@ pub fn get_checksum_from_file(file: &String, print_progress: bool) -> Result<Che
         read_count = f.read(&mut buffer).unwrap();
         read_pos += read_count as u64;
         if read_count == 0 { break; }
+        throttle::consume(read_count);
 
-        digest_crc32.write(&buffer[0..read_count]);
-        context_md5.consume(&buffer[0..read_count]);
+        for hasher in hashers.iter_mut() {
+            hasher.update(&buffer[0..read_count]);
+        }
 
         if print_progress {
             let perc = prog_bar_size * read_pos / read_max;
@@ -178,20 +564,181 @@ pub fn get_checksum_from_file(file: &String, print_progress: bool) -> Result<Che
         println!(" {} bytes read", read_pos);
     }
 
-    let digest_md5 = context_md5.compute();
-
     let file_path = Path::new(file);
     let path = String::from(file_path.to_str().unwrap());
     let filename = String::from(file_path.file_name().unwrap().to_str().unwrap());
 
+    let mut digests = BTreeMap::new();
+    for hasher in hashers {
+        digests.insert(hasher.algorithm(), hasher.finish());
+    }
+
     let entry = ChecksumEntry {
         filename: filename,
         path: path,
-        checksum_crc32: Some(digest_crc32.sum32()),
-        checksum_md5: Some(digest_md5.0),
+        digests,
         valid: true,
         state: 0,
+        length: Some(read_max),
+        ..Default::default()
     };
 
     Ok(entry)
-}
\ No newline at end of file
+}
+
+// Digests of a part of a file
+pub fn get_checksums_of_range(file: &str, offset: u64, length: u64, algorithms: &[HashAlgorithm]) -> Result<BTreeMap<HashAlgorithm, Vec<u8>>, std::io::Error> {
+    let mut hashers: Vec<Hasher> = algorithms.iter().map(|a| Hasher::new(*a)).collect();
+
+    let mut f = File::open(file)?;
+    f.seek(SeekFrom::Start(offset))?;
+    let mut reader = f.take(length);
+
+    let mut buffer = vec![0u8; 1024*1024];
+    let mut read_pos: u64 = 0;
+    loop {
+        let read_count = reader.read(&mut buffer)?;
+        if read_count == 0 { break; }
+        throttle::consume(read_count);
+        read_pos += read_count as u64;
+        for hasher in hashers.iter_mut() {
+            hasher.update(&buffer[0..read_count]);
+        }
+    }
+    if read_pos < length {
+        return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "range exceeds the end of the file"));
+    }
+
+    Ok(hashers.into_iter().map(|h| (h.algorithm(), h.finish())).collect())
+}
+
+pub const FIRST_16K: u64 = 16 * 1024;
+const SLICE_CHUNK_SIZE: u64 = 1024 * 1024;
+
+// Length and MD5 of the first 16k of a file, as used by PAR2 to identify files quickly
+pub fn get_first_16k_md5_from_file(file: &String) -> Result<ChecksumEntry, std::io::Error> {
+    let f = File::open(file)?;
+    let length = f.metadata()?.len();
+
+    let mut buffer: Vec<u8> = Vec::new();
+    f.take(FIRST_16K).read_to_end(&mut buffer)?;
+
+    let mut entry = ChecksumEntry::from_path(file, length);
+    entry.first_16k_md5 = Some(md5::compute(&buffer).0);
+
+    Ok(entry)
+}
+
+pub fn get_slice_checksums_from_file(file: &String, slice_size: u64) -> Result<Vec<SliceChecksum>, std::io::Error> {
+    let mut slices: Vec<SliceChecksum> = Vec::new();
+
+    if slice_size == 0 {
+        return Ok(slices);
+    }
+
+    let mut f = File::open(file)?;
+    while let Some((slice, read_count)) = read_slice_checksum(&mut f, slice_size)? {
+        slices.push(slice);
+        if read_count < slice_size { break; }
+    }
+
+    Ok(slices)
+}
+
+// MD5 and CRC32 of the next slice of the reader. The last slice is checksummed as if it
+// was padded with zeros. The slice size is taken from the catalog, so the slice is read
+// in chunks. Returns the bytes read, or None at the end of the reader.
+pub fn read_slice_checksum<R: Read>(reader: &mut R, slice_size: u64) -> Result<Option<(SliceChecksum, u64)>, std::io::Error> {
+    let mut md5 = Hasher::new(HashAlgorithm::Md5);
+    let mut crc32 = Hasher::new(HashAlgorithm::Crc32);
+    let mut buffer = vec![0u8; SLICE_CHUNK_SIZE.min(slice_size) as usize];
+    let mut read_count: u64 = 0;
+    let mut at_end = false;
+
+    let mut remaining = slice_size;
+    while remaining > 0 {
+        let chunk = &mut buffer[..remaining.min(SLICE_CHUNK_SIZE) as usize];
+        let mut filled = 0;
+        while !at_end && filled < chunk.len() {
+            let n = reader.read(&mut chunk[filled..])?;
+            if n == 0 {
+                at_end = true;
+            }
+            throttle::consume(n);
+            filled += n;
+        }
+        if read_count == 0 && filled == 0 {
+            return Ok(None);
+        }
+        for b in chunk[filled..].iter_mut() {
+            *b = 0;
+        }
+
+        md5.update(chunk);
+        crc32.update(chunk);
+        read_count += filled as u64;
+        remaining -= chunk.len() as u64;
+    }
+
+    let mut slice = SliceChecksum { md5: [0; 16], crc32: 0 };
+    slice.md5.copy_from_slice(&md5.finish());
+    let crc32 = crc32.finish();
+    slice.crc32 = u32::from_be_bytes([crc32[0], crc32[1], crc32[2], crc32[3]]);
+    Ok(Some((slice, read_count)))
+}
+
+// Number of slices of a file matching the slices of a catalog entry at the same position
+pub fn count_matching_slices(file_slices: &[SliceChecksum], entry_slices: &[SliceChecksum]) -> usize {
+    file_slices.iter()
+        .zip(entry_slices.iter())
+        .filter(|(fs, es)| fs.md5 == es.md5)
+        .count()
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hash_backend;
+
+    fn test_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    // CRC32 of the chunks combined, as the hash engine does it
+    fn combined_crc32(data: &[u8], sizes: &[usize]) -> u32 {
+        let mut crc = 0;
+        let mut start = 0;
+        for size in sizes {
+            let chunk = &data[start..start + size];
+            crc = crc32_combine(crc, hash_backend::crc32(chunk), chunk.len() as u64);
+            start += size;
+        }
+        assert_eq!(start, data.len());
+        crc
+    }
+
+    #[test]
+    fn combined_crc32_matches_one_shot() {
+        let data = test_data(100_003);
+        let expected = hash_backend::crc32(&data);
+        let splits: Vec<Vec<usize>> = vec![
+            vec![100_003],
+            vec![50_000, 50_003],
+            vec![1, 100_002],
+            vec![100_002, 1],
+            vec![0, 100_003, 0],
+            vec![33_333, 0, 33_333, 33_337],
+            vec![7; 14_286].into_iter().chain(vec![1]).collect(),
+            vec![65_536, 34_467],
+        ];
+        for sizes in &splits {
+            assert_eq!(combined_crc32(&data, sizes), expected, "split {:?}", &sizes[..sizes.len().min(4)]);
+        }
+    }
+
+    #[test]
+    fn combined_crc32_of_empty_data() {
+        assert_eq!(combined_crc32(&[], &[]), hash_backend::crc32(&[]));
+        assert_eq!(combined_crc32(&[], &[0, 0]), 0);
+        assert_eq!(crc32_combine(0x1234_5678, 0, 0), 0x1234_5678);
+    }
+}
@@ -0,0 +1,117 @@
+// reverse-checksum-renamer
+//
+// Copyright (C) 2020  Martin Feil aka. SGDW
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>
+
+// Implementations of CRC32 and MD5 to choose from. The accelerated ones are used if
+// the CPU supports them, the crates used before remain as fallback.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+extern crate crc;
+use self::crc::crc32;
+
+extern crate crc32fast;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Crc32Backend {
+    Table,     // crc 1.x, table driven
+    Crc32Fast, // crc32fast, PCLMULQDQ on x86 or the CRC instructions of ARMv8
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Md5Backend {
+    Md5,       // md5 0.3
+    RustCrypto, // md-5
+}
+
+const BACKEND_AUTO: usize = 0;
+const BACKEND_FALLBACK: usize = 1;
+
+static SELECTION: AtomicUsize = AtomicUsize::new(BACKEND_AUTO);
+
+// Forces the fallback implementations, e.g. to rule out a broken backend
+pub fn set_use_fallback(use_fallback: bool) {
+    SELECTION.store(if use_fallback { BACKEND_FALLBACK } else { BACKEND_AUTO }, Ordering::SeqCst);
+}
+
+fn use_fallback() -> bool {
+    SELECTION.load(Ordering::SeqCst) == BACKEND_FALLBACK
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn has_crc32_instructions() -> bool {
+    is_x86_feature_detected!("pclmulqdq") && is_x86_feature_detected!("sse4.1")
+}
+
+#[cfg(target_arch = "aarch64")]
+pub fn has_crc32_instructions() -> bool {
+    is_aarch64_feature_detected!("crc")
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn has_crc32_instructions() -> bool {
+    false
+}
+
+// SHA extensions, which the sha1 and sha2 crates detect on their own
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn has_sha_instructions() -> bool {
+    is_x86_feature_detected!("sha")
+}
+
+#[cfg(target_arch = "aarch64")]
+pub fn has_sha_instructions() -> bool {
+    is_aarch64_feature_detected!("sha2")
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn has_sha_instructions() -> bool {
+    false
+}
+
+pub fn crc32_backend() -> Crc32Backend {
+    if !use_fallback() && has_crc32_instructions() {
+        Crc32Backend::Crc32Fast
+    } else {
+        Crc32Backend::Table
+    }
+}
+
+// There are no MD5 instructions, md-5 is just faster
+pub fn md5_backend() -> Md5Backend {
+    if use_fallback() { Md5Backend::Md5 } else { Md5Backend::RustCrypto }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    match crc32_backend() {
+        Crc32Backend::Crc32Fast => crc32fast::hash(data),
+        Crc32Backend::Table => crc32::checksum_ieee(data),
+    }
+}
+
+// e.g. "CRC32: crc32fast (CPU instructions), MD5: md-5, SHA: sha1/sha2 (SHA extensions)"
+pub fn describe() -> String {
+    let crc32 = match crc32_backend() {
+        Crc32Backend::Crc32Fast => "crc32fast (CPU instructions)",
+        Crc32Backend::Table => "crc (table)",
+    };
+    let md5 = match md5_backend() {
+        Md5Backend::RustCrypto => "md-5",
+        Md5Backend::Md5 => "md5",
+    };
+    let sha = if has_sha_instructions() { "sha1/sha2 (SHA extensions)" } else { "sha1/sha2 (software)" };
+    format!("CRC32: {}, MD5: {}, SHA: {}", crc32, md5, sha)
+}
@@ -0,0 +1,358 @@
+// reverse-checksum-renamer
+//
+// Copyright (C) 2020  Martin Feil aka. SGDW
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>
+
+// On-disk cache of the digests of files, so reruns on the same directory do not
+// have to read unchanged files again. Files are identified by device and inode,
+// so renamed files are found too. A changed size or mtime invalidates the entry.
+// Checkpoints of interrupted scans are written in the same format.
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use utils;
+use file_verification::HashAlgorithm;
+
+const CACHE_HEADER: &str = "# reverse-checksum-renamer hash cache v1";
+const CACHE_FILENAME: &str = "hashes.txt";
+const CHECKPOINT_HEADER: &str = "# reverse-checksum-renamer checkpoint v1";
+const CHECKPOINT_FILENAME: &str = ".reverse-checksum-renamer.resume";
+
+// Entries not used for this long are pruned when saving
+const MAX_UNUSED_SECS: u64 = 90 * 24 * 60 * 60;
+const MAX_ENTRIES: usize = 1_000_000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct FileKey {
+    pub dev: u64,
+    pub inode: u64,
+    pub size: u64,
+    pub mtime_ns: i64,
+}
+
+struct CacheEntry {
+    size: u64,
+    mtime_ns: i64,
+    last_used: u64,
+    digests: BTreeMap<HashAlgorithm, Vec<u8>>,
+}
+
+pub struct HashCache {
+    path: PathBuf,
+    entries: HashMap<(u64, u64), CacheEntry>,
+    modified: bool,
+}
+
+#[cfg(unix)]
+pub fn get_file_key(file: &str) -> Option<FileKey> {
+    use std::os::unix::fs::MetadataExt;
+
+    let meta = fs::metadata(file).ok()?;
+    Some(FileKey {
+        dev: meta.dev(),
+        inode: meta.ino(),
+        size: meta.size(),
+        mtime_ns: meta.mtime() * 1_000_000_000 + meta.mtime_nsec(),
+    })
+}
+
+#[cfg(not(unix))]
+pub fn get_file_key(_file: &str) -> Option<FileKey> {
+    None
+}
+
+// $XDG_CACHE_HOME/reverse-checksum-renamer/hashes.txt or ~/.cache/...
+pub fn default_cache_path() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CACHE_HOME").filter(|v| !v.is_empty()) {
+        Some(v) => PathBuf::from(v),
+        None => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+    };
+    Some(base.join("reverse-checksum-renamer").join(CACHE_FILENAME))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl HashCache {
+    // A missing cache file is an empty cache, broken lines are skipped
+    pub fn load(path: &Path) -> HashCache {
+        let mut cache = HashCache {
+            path: path.to_path_buf(),
+            entries: HashMap::new(),
+            modified: false,
+        };
+
+        if let Ok(fh) = File::open(path) {
+            for line in BufReader::new(fh).lines() {
+                let line = match line {
+                    Ok(v) => v,
+                    Err(_e) => break,
+                };
+                if line.starts_with('#') {
+                    continue;
+                }
+                if let Some((key, entry)) = parse_cache_line(&line) {
+                    cache.entries.insert(key, entry);
+                }
+            }
+        }
+        cache
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Returns the digests only if all requested algorithms are cached
+    pub fn get(&mut self, key: &FileKey, algorithms: &[HashAlgorithm]) -> Option<BTreeMap<HashAlgorithm, Vec<u8>>> {
+        let stale = match self.entries.get_mut(&(key.dev, key.inode)) {
+            Some(entry) => {
+                if entry.size != key.size || entry.mtime_ns != key.mtime_ns {
+                    true
+                } else if algorithms.iter().all(|a| entry.digests.contains_key(a)) {
+                    entry.last_used = now_secs();
+                    self.modified = true;
+                    return Some(entry.digests.iter()
+                        .filter(|&(a, _)| algorithms.contains(a))
+                        .map(|(a, v)| (*a, v.clone()))
+                        .collect());
+                } else {
+                    false
+                }
+            },
+            None => false,
+        };
+
+        if stale {
+            self.entries.remove(&(key.dev, key.inode));
+            self.modified = true;
+        }
+        None
+    }
+
+    // Digests of an unchanged file are added to the ones already cached
+    pub fn insert(&mut self, key: &FileKey, digests: &BTreeMap<HashAlgorithm, Vec<u8>>) {
+        let entry = self.entries.entry((key.dev, key.inode)).or_insert_with(|| CacheEntry {
+            size: key.size,
+            mtime_ns: key.mtime_ns,
+            last_used: 0,
+            digests: BTreeMap::new(),
+        });
+        if entry.size != key.size || entry.mtime_ns != key.mtime_ns {
+            entry.size = key.size;
+            entry.mtime_ns = key.mtime_ns;
+            entry.digests.clear();
+        }
+        entry.last_used = now_secs();
+        entry.digests.extend(digests.iter().map(|(a, v)| (*a, v.clone())));
+        self.modified = true;
+    }
+
+    // Removes entries not used for a long time and the oldest ones above the limit
+    pub fn prune(&mut self) -> usize {
+        let num_entries = self.entries.len();
+        let min_last_used = now_secs().saturating_sub(MAX_UNUSED_SECS);
+        self.entries.retain(|_, e| e.last_used >= min_last_used);
+
+        if self.entries.len() > MAX_ENTRIES {
+            let mut last_used: Vec<u64> = self.entries.values().map(|e| e.last_used).collect();
+            last_used.sort_unstable_by(|a, b| b.cmp(a));
+            let min_last_used = last_used[MAX_ENTRIES - 1];
+            self.entries.retain(|_, e| e.last_used >= min_last_used);
+        }
+
+        let pruned = num_entries - self.entries.len();
+        if pruned > 0 {
+            self.modified = true;
+        }
+        pruned
+    }
+
+    // Written to a temporary file first, so an interrupted run keeps the old cache
+    pub fn save(&mut self) -> Result<(), std::io::Error> {
+        if !self.modified {
+            return Ok(());
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            writeln!(writer, "{}", CACHE_HEADER)?;
+            for (&(dev, inode), e) in &self.entries {
+                writeln!(writer, "{}", format_cache_line(dev, inode, e.size, e.mtime_ns, e.last_used, &e.digests))?;
+            }
+            writer.flush()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        self.modified = false;
+        Ok(())
+    }
+}
+
+// Sidecar file in the input directory keeping the digests of an interrupted scan
+pub fn checkpoint_path(input_dir: &Path) -> PathBuf {
+    input_dir.join(CHECKPOINT_FILENAME)
+}
+
+// Digests of a running scan, appended file by file as they are finished. The lines
+// are the ones of the cache, so a checkpoint is loaded as a HashCache to resume.
+pub struct Checkpoint {
+    file: File,
+}
+
+impl Checkpoint {
+    // Starts a new checkpoint, or continues the existing one when resuming
+    pub fn open(path: &Path, resume: bool) -> Result<Checkpoint, std::io::Error> {
+        let append = resume && path.exists();
+        let mut file = OpenOptions::new().write(true).create(true).append(append).truncate(!append).open(path)?;
+        if !append {
+            writeln!(file, "{}", CHECKPOINT_HEADER)?;
+        }
+        Ok(Checkpoint { file })
+    }
+
+    // Each line is written at once, so an interrupted run loses at most the last one
+    pub fn append(&mut self, key: &FileKey, digests: &BTreeMap<HashAlgorithm, Vec<u8>>) -> Result<(), std::io::Error> {
+        let line = format_cache_line(key.dev, key.inode, key.size, key.mtime_ns, now_secs(), digests) + "\n";
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()
+    }
+}
+
+fn format_cache_line(dev: u64, inode: u64, size: u64, mtime_ns: i64, last_used: u64, digests: &BTreeMap<HashAlgorithm, Vec<u8>>) -> String {
+    let digests: Vec<String> = digests.iter()
+        .map(|(a, v)| format!("{}:{}", a.name(), utils::byte_array_to_hex(v)))
+        .collect();
+    format!("{} {} {} {} {} {}", dev, inode, size, mtime_ns, last_used, digests.join(" "))
+}
+
+// <dev> <inode> <size> <mtime_ns> <last_used> <algorithm>:<hex> ...
+fn parse_cache_line(line: &str) -> Option<((u64, u64), CacheEntry)> {
+    let mut fields = line.split_whitespace();
+    let dev = fields.next()?.parse::<u64>().ok()?;
+    let inode = fields.next()?.parse::<u64>().ok()?;
+    let mut entry = CacheEntry {
+        size: fields.next()?.parse::<u64>().ok()?,
+        mtime_ns: fields.next()?.parse::<i64>().ok()?,
+        last_used: fields.next()?.parse::<u64>().ok()?,
+        digests: BTreeMap::new(),
+    };
+
+    for field in fields {
+        let mut parts = field.splitn(2, ':');
+        let algorithm = HashAlgorithm::from_name(parts.next()?)?;
+        let value = utils::hex_to_byte_array(parts.next()?)?;
+        // A line cut off within a digest is skipped
+        if value.len() != algorithm.digest_len() {
+            return None;
+        }
+        entry.digests.insert(algorithm, value);
+    }
+    if entry.digests.is_empty() {
+        return None;
+    }
+    Some(((dev, inode), entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    const LINE: &str = "1 2 3 4 5 crc32:0badf00d md5:000102030405060708090a0b0c0d0e0f";
+
+    fn test_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("rcr-test-{}-{}", name, process::id()))
+    }
+
+    fn key(size: u64, mtime_ns: i64) -> FileKey {
+        FileKey { dev: 1, inode: 2, size, mtime_ns }
+    }
+
+    #[test]
+    fn parses_a_cache_line() {
+        let ((dev, inode), entry) = parse_cache_line(LINE).unwrap();
+        assert_eq!((dev, inode, entry.size, entry.mtime_ns, entry.last_used), (1, 2, 3, 4, 5));
+        assert_eq!(entry.digests[&HashAlgorithm::Crc32], vec![0x0b, 0xad, 0xf0, 0x0d]);
+        assert_eq!(entry.digests[&HashAlgorithm::Md5].len(), 16);
+
+        let digests = entry.digests.clone();
+        assert_eq!(format_cache_line(1, 2, 3, 4, 5, &digests), LINE);
+    }
+
+    #[test]
+    fn skips_malformed_lines() {
+        for line in &["", "1 2 3 4 5", "1 2 3 4 crc32:0badf00d", "x 2 3 4 5 crc32:0badf00d",
+                "1 2 -3 4 5 crc32:0badf00d", "1 2 3 4 5 crc32", "1 2 3 4 5 whirlpool:0badf00d",
+                "1 2 3 4 5 crc32:0badf00x", "1 2 3 4 5 crc32:0badf00d0d"] {
+            assert!(parse_cache_line(line).is_none(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn skips_a_truncated_last_line() {
+        let path = test_path("checkpoint");
+        let digests = parse_cache_line(LINE).unwrap().1.digests;
+        {
+            let mut checkpoint = Checkpoint::open(&path, false).unwrap();
+            checkpoint.append(&key(3, 4), &digests).unwrap();
+        }
+        // An interrupted write of a second file, cut off at a byte boundary of its MD5
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        write!(file, "1 7 3 4 5 crc32:0badf00d md5:0001").unwrap();
+        drop(file);
+
+        let mut cache = HashCache::load(&path);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get(&key(3, 4), &[HashAlgorithm::Md5]).is_some());
+
+        // Resuming appends to the checkpoint
+        Checkpoint::open(&path, true).unwrap();
+        assert!(fs::read_to_string(&path).unwrap().starts_with(CHECKPOINT_HEADER));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn drops_entries_of_changed_files() {
+        let path = test_path("cache");
+        fs::write(&path, format!("{}\n{}\n", CACHE_HEADER, LINE)).unwrap();
+        let mut cache = HashCache::load(&path);
+        assert!(cache.get(&key(3, 4), &[HashAlgorithm::Crc32, HashAlgorithm::Sha1]).is_none());
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get(&key(3, 4), &[HashAlgorithm::Crc32]).is_some());
+
+        assert!(cache.get(&key(3, 6), &[HashAlgorithm::Crc32]).is_none());
+        assert!(cache.is_empty());
+
+        let mut cache = HashCache::load(&path);
+        assert!(cache.get(&key(8, 4), &[HashAlgorithm::Crc32]).is_none());
+        assert!(cache.is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,544 @@
+// reverse-checksum-renamer
+//
+// Copyright (C) 2020  Martin Feil aka. SGDW
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>
+
+// Hashing of many files as a pipeline: reader threads read the files in chunks into
+// buffers of a bounded pool, hasher threads process the chunks. CRC32 is calculated
+// per chunk and combined, so a single large file is spread over all hashers. The
+// other algorithms have to see the chunks in order, but different algorithms and
+// different files still run in parallel. Files are read per block device, with a
+// single reader for spinning disks and the larger files first.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use file_verification;
+use hash_backend;
+use file_verification::{HashAlgorithm, SliceChecksum};
+use storage;
+use throttle;
+
+pub const DEFAULT_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+pub const DEFAULT_MAX_MEMORY: usize = 256 * 1024 * 1024;
+pub const DEFAULT_SSD_READERS: usize = 4;
+const PROGRESS_MIN_CHUNKS: u64 = 100; // larger files show how much is read
+
+pub struct HashEngineConfig {
+    pub ssd_readers: usize, // readers per device which is not a spinning disk
+    pub hashers: usize,
+    pub chunk_size: usize,
+    pub max_memory: usize, // upper limit of all buffers
+    pub verbose: bool,
+    pub cancel: Arc<AtomicBool>, // stops reading, files not read completely are dropped
+}
+
+impl Default for HashEngineConfig {
+    fn default() -> HashEngineConfig {
+        HashEngineConfig {
+            ssd_readers: DEFAULT_SSD_READERS,
+            hashers: 1,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            max_memory: DEFAULT_MAX_MEMORY,
+            verbose: false,
+            cancel: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+// Files of one block device, largest first
+struct DeviceQueue {
+    dev: u64,
+    rotational: Option<bool>,
+    files: Vec<usize>,
+    next_file: AtomicUsize,
+}
+
+fn get_device_queues(files: &[String]) -> Vec<DeviceQueue> {
+    let mut by_device: HashMap<u64, Vec<(usize, u64)>> = HashMap::new();
+    for (index, file) in files.iter().enumerate() {
+        let (dev, length) = storage::get_device_and_length(file).unwrap_or((0, 0));
+        by_device.entry(dev).or_default().push((index, length));
+    }
+
+    let mut queues: Vec<DeviceQueue> = by_device.into_iter().map(|(dev, mut files)| {
+        files.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        DeviceQueue {
+            dev,
+            rotational: storage::is_rotational(dev),
+            files: files.into_iter().map(|f| f.0).collect(),
+            next_file: AtomicUsize::new(0),
+        }
+    }).collect();
+    queues.sort_by_key(|q| q.dev);
+    queues
+}
+
+// Buffers are reused. Readers block if all of them are in use.
+struct BufferPool {
+    buffers: Mutex<(Vec<Vec<u8>>, usize)>, // (free buffers, allocated buffers)
+    released: Condvar,
+    buffer_size: usize,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    fn acquire(&self) -> Vec<u8> {
+        let mut buffers = self.buffers.lock().unwrap();
+        loop {
+            if let Some(buffer) = buffers.0.pop() {
+                return buffer;
+            }
+            if buffers.1 < self.max_buffers {
+                buffers.1 += 1;
+                return vec![0u8; self.buffer_size];
+            }
+            buffers = self.released.wait(buffers).unwrap();
+        }
+    }
+
+    fn release(&self, buffer: Vec<u8>) {
+        self.buffers.lock().unwrap().0.push(buffer);
+        self.released.notify_one();
+    }
+}
+
+// A chunk goes back to the pool when the last hasher is done with it
+struct Chunk {
+    pool: Arc<BufferPool>,
+    buffer: Vec<u8>,
+    len: usize,
+}
+
+impl Chunk {
+    fn data(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        self.pool.release(mem::take(&mut self.buffer));
+    }
+}
+
+// Chunks of one file for an algorithm which needs them in order
+struct Stream {
+    hasher: Option<file_verification::Hasher>,
+    next_chunk: usize,
+    waiting: BTreeMap<usize, Arc<Chunk>>,
+    busy: bool,
+}
+
+struct FileState {
+    index: usize,
+    path: String,
+    length: Mutex<u64>,
+    crc32_parts: Mutex<BTreeMap<usize, (u32, u64)>>, // chunk -> (CRC32, length)
+    with_crc32: bool,
+    streams: Vec<Mutex<Stream>>,
+    pending: AtomicUsize, // chunks not hashed yet plus one until the file is read
+    failed: AtomicBool,
+}
+
+enum Task {
+    Crc32,
+    Stream(usize),
+}
+
+struct Job {
+    file: Arc<FileState>,
+    chunk_index: usize,
+    chunk: Arc<Chunk>,
+    task: Task,
+}
+
+struct Progress {
+    num_files: usize,
+    finished: AtomicUsize,
+    results: Mutex<mpsc::Sender<(usize, file_verification::ChecksumEntry)>>,
+    cancel: Arc<AtomicBool>,
+}
+
+pub fn hash_files(files: &[String], algorithms: &[HashAlgorithm], config: &HashEngineConfig) -> Vec<file_verification::ChecksumEntry> {
+    hash_files_with(files, algorithms, config, |_| {})
+}
+
+// Calls on_finished for each file as soon as it is hashed, e.g. to checkpoint the results
+pub fn hash_files_with<F>(files: &[String], algorithms: &[HashAlgorithm], config: &HashEngineConfig, mut on_finished: F)
+    -> Vec<file_verification::ChecksumEntry> where F: FnMut(&file_verification::ChecksumEntry) {
+    let chunk_size = config.chunk_size.max(1);
+    let pool = Arc::new(BufferPool {
+        buffers: Mutex::new((Vec::new(), 0)),
+        released: Condvar::new(),
+        buffer_size: chunk_size,
+        max_buffers: (config.max_memory / chunk_size).max(2),
+    });
+    let (result_sender, result_receiver) = mpsc::channel::<(usize, file_verification::ChecksumEntry)>();
+    let progress = Arc::new(Progress {
+        num_files: files.len(),
+        finished: AtomicUsize::new(0),
+        results: Mutex::new(result_sender),
+        cancel: config.cancel.clone(),
+    });
+
+    let (sender, receiver) = mpsc::channel::<Job>();
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    let mut hashers: Vec<thread::JoinHandle<()>> = Vec::new();
+    for _i in 0..config.hashers.max(1) {
+        let receiver = receiver.clone();
+        let progress = progress.clone();
+        hashers.push(thread::spawn(move || {
+            loop {
+                let job = match receiver.lock().unwrap().recv() {
+                    Ok(v) => v,
+                    Err(_e) => break,
+                };
+                run_job(job, &progress);
+            }
+        }));
+    }
+
+    let files = Arc::new(files.to_vec());
+    let algorithms = Arc::new(algorithms.to_vec());
+
+    let mut readers: Vec<thread::JoinHandle<()>> = Vec::new();
+    for queue in get_device_queues(&files) {
+        let num_readers = get_num_readers(&queue, config);
+        if config.verbose {
+            let kind = match queue.rotational {
+                Some(true) => "rotational",
+                Some(false) => "non-rotational",
+                None => "unknown",
+            };
+            println!("Device {} ({}): {} files, {} readers", storage::device_name(queue.dev), kind, queue.files.len(), num_readers);
+        }
+
+        let queue = Arc::new(queue);
+        for _i in 0..num_readers {
+            let queue = queue.clone();
+            let files = files.clone();
+            let algorithms = algorithms.clone();
+            let pool = pool.clone();
+            let progress = progress.clone();
+            let sender = sender.clone();
+            readers.push(thread::spawn(move || {
+                loop {
+                    let next_file = queue.next_file.fetch_add(1, Ordering::Relaxed);
+                    if next_file >= queue.files.len() || progress.cancel.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let index = queue.files[next_file];
+                    read_file(index, &files[index], &algorithms, &pool, &sender, &progress);
+                }
+            }));
+        }
+    }
+    drop(sender);
+    drop(progress);
+
+    // Ends when all threads have dropped their progress
+    let mut results: Vec<(usize, file_verification::ChecksumEntry)> = Vec::new();
+    for result in result_receiver {
+        on_finished(&result.1);
+        results.push(result);
+    }
+
+    for handle in readers {
+        handle.join().unwrap();
+    }
+    for handle in hashers {
+        handle.join().unwrap();
+    }
+
+    results.sort_by_key(|r| r.0);
+    results.into_iter().map(|r| r.1).collect()
+}
+
+fn get_num_readers(queue: &DeviceQueue, config: &HashEngineConfig) -> usize {
+    match queue.rotational {
+        Some(true) => 1,
+        _ => config.ssd_readers.max(1).min(queue.files.len()),
+    }
+}
+
+// PAR2 slice checksums of (file, slice size) pairs for scoring damaged files. The files
+// are read per block device like in hash_files, each reader checksums its own file.
+// Files which could not be read have no slices.
+pub fn slice_checksums_of_files(files: &[(String, u64)], config: &HashEngineConfig) -> Vec<Vec<SliceChecksum>> {
+    let paths: Vec<String> = files.iter().map(|f| f.0.clone()).collect();
+    let files = Arc::new(files.to_vec());
+    let results: Arc<Mutex<Vec<Vec<SliceChecksum>>>> = Arc::new(Mutex::new(vec![Vec::new(); files.len()]));
+
+    let mut readers: Vec<thread::JoinHandle<()>> = Vec::new();
+    for queue in get_device_queues(&paths) {
+        let num_readers = get_num_readers(&queue, config);
+        let queue = Arc::new(queue);
+        for _i in 0..num_readers {
+            let queue = queue.clone();
+            let files = files.clone();
+            let results = results.clone();
+            let cancel = config.cancel.clone();
+            readers.push(thread::spawn(move || {
+                loop {
+                    let next_file = queue.next_file.fetch_add(1, Ordering::Relaxed);
+                    if next_file >= queue.files.len() || cancel.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let index = queue.files[next_file];
+                    let (ref path, slice_size) = files[index];
+                    match file_verification::get_slice_checksums_from_file(path, slice_size) {
+                        Ok(v) => results.lock().unwrap()[index] = v,
+                        Err(_e) => println!("Could not read slices of {:?}: {}", path, _e),
+                    }
+                }
+            }));
+        }
+    }
+
+    for handle in readers {
+        handle.join().unwrap();
+    }
+    let slices = mem::take(&mut *results.lock().unwrap());
+    slices
+}
+
+fn read_file(index: usize, path: &str, algorithms: &[HashAlgorithm], pool: &Arc<BufferPool>,
+             sender: &mpsc::Sender<Job>, progress: &Progress) {
+    let file = Arc::new(FileState {
+        index,
+        path: path.to_string(),
+        length: Mutex::new(0),
+        crc32_parts: Mutex::new(BTreeMap::new()),
+        with_crc32: algorithms.contains(&HashAlgorithm::Crc32),
+        streams: algorithms.iter()
+            .filter(|a| **a != HashAlgorithm::Crc32)
+            .map(|a| Mutex::new(Stream {
+                hasher: Some(file_verification::Hasher::new(*a)),
+                next_chunk: 0,
+                waiting: BTreeMap::new(),
+                busy: false,
+            }))
+            .collect(),
+        pending: AtomicUsize::new(1),
+        failed: AtomicBool::new(false),
+    });
+
+    println!("Checking file '{}' ...", path);
+    let mut fh = match File::open(path) {
+        Ok(v) => v,
+        Err(_e) => {
+            println!("Could not read {:?}: {}", path, _e);
+            file.failed.store(true, Ordering::SeqCst);
+            finish_job(&file, progress);
+            return;
+        }
+    };
+
+    let file_length = fh.metadata().map(|m| m.len()).unwrap_or(0);
+    let show_progress = file_length / pool.buffer_size as u64 >= PROGRESS_MIN_CHUNKS;
+    let mut read_percent = 0;
+
+    let tasks_per_chunk = file.streams.len() + if file.with_crc32 { 1 } else { 0 };
+    let mut chunk_index = 0;
+    loop {
+        let mut buffer = pool.acquire();
+        let len = match read_full(&mut fh, &mut buffer) {
+            Ok(v) => v,
+            Err(_e) => {
+                println!("Could not read {:?}: {}", path, _e);
+                pool.release(buffer);
+                file.failed.store(true, Ordering::SeqCst);
+                break;
+            }
+        };
+        if len == 0 {
+            pool.release(buffer);
+            break;
+        }
+        if progress.cancel.load(Ordering::SeqCst) {
+            pool.release(buffer);
+            file.failed.store(true, Ordering::SeqCst);
+            break;
+        }
+        let read_length = {
+            let mut length = file.length.lock().unwrap();
+            *length += len as u64;
+            *length
+        };
+        if show_progress {
+            let percent = (read_length * 100 / file_length.max(1)) / 10 * 10;
+            if percent > read_percent && percent < 100 {
+                println!("Checking file '{}' ... {}%", path, percent);
+                read_percent = percent;
+            }
+        }
+
+        let chunk = Arc::new(Chunk { pool: pool.clone(), buffer, len });
+        file.pending.fetch_add(tasks_per_chunk, Ordering::SeqCst);
+        if file.with_crc32 {
+            sender.send(Job { file: file.clone(), chunk_index, chunk: chunk.clone(), task: Task::Crc32 }).unwrap();
+        }
+        for stream in 0..file.streams.len() {
+            sender.send(Job { file: file.clone(), chunk_index, chunk: chunk.clone(), task: Task::Stream(stream) }).unwrap();
+        }
+        chunk_index += 1;
+    }
+    finish_job(&file, progress);
+}
+
+// Fills the buffer unless the end of the file is reached
+fn read_full(fh: &mut File, buffer: &mut [u8]) -> Result<usize, std::io::Error> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match fh.read(&mut buffer[len..]) {
+            Ok(0) => break,
+            Ok(v) => {
+                len += v;
+                throttle::consume(v);
+            },
+            Err(ref _e) if _e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(_e) => return Err(_e),
+        }
+    }
+    Ok(len)
+}
+
+fn run_job(job: Job, progress: &Progress) {
+    match job.task {
+        Task::Crc32 => {
+            let data = job.chunk.data();
+            let crc = hash_backend::crc32(data);
+            job.file.crc32_parts.lock().unwrap().insert(job.chunk_index, (crc, data.len() as u64));
+            drop(job.chunk);
+            finish_job(&job.file, progress);
+        },
+        Task::Stream(stream_index) => {
+            let file = job.file;
+            let stream = &file.streams[stream_index];
+
+            let mut state = stream.lock().unwrap();
+            state.waiting.insert(job.chunk_index, job.chunk);
+            if state.busy {
+                return; // the other hasher will process it in order
+            }
+            state.busy = true;
+
+            loop {
+                let next_chunk = state.next_chunk;
+                let chunk = match state.waiting.remove(&next_chunk) {
+                    Some(v) => v,
+                    None => {
+                        state.busy = false;
+                        break;
+                    }
+                };
+                let mut hasher = state.hasher.take().unwrap();
+                state.next_chunk += 1;
+                drop(state);
+
+                hasher.update(chunk.data());
+                drop(chunk);
+
+                state = stream.lock().unwrap();
+                state.hasher = Some(hasher);
+                drop(state);
+                finish_job(&file, progress);
+                state = stream.lock().unwrap();
+            }
+        },
+    }
+}
+
+// The last job of a file puts the digests together
+fn finish_job(file: &Arc<FileState>, progress: &Progress) {
+    if file.pending.fetch_sub(1, Ordering::SeqCst) != 1 {
+        return;
+    }
+    if file.failed.load(Ordering::SeqCst) {
+        // Files not read completely because of Ctrl-C are not counted
+        if !progress.cancel.load(Ordering::SeqCst) {
+            let finished = progress.finished.fetch_add(1, Ordering::SeqCst);
+            println!("[{} of {}] Could not check file '{}', skipped", finished + 1, progress.num_files, file.path);
+        }
+        return;
+    }
+
+    let mut digests: BTreeMap<HashAlgorithm, Vec<u8>> = BTreeMap::new();
+    if file.with_crc32 {
+        let crc = file.crc32_parts.lock().unwrap().values()
+            .fold(0, |crc, &(part, len)| file_verification::crc32_combine(crc, part, len));
+        digests.insert(HashAlgorithm::Crc32, crc.to_be_bytes().to_vec());
+    }
+    for stream in &file.streams {
+        let hasher = stream.lock().unwrap().hasher.take().unwrap();
+        digests.insert(hasher.algorithm(), hasher.finish());
+    }
+
+    let length = *file.length.lock().unwrap();
+    let mut entry = file_verification::ChecksumEntry::from_path(&file.path, length);
+    entry.digests = digests;
+
+    let finished = progress.finished.fetch_add(1, Ordering::SeqCst);
+    println!("[{} of {}] Finished checking file '{}' ...", finished + 1, progress.num_files, file.path);
+    let _ = progress.results.lock().unwrap().send((file.index, entry));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn chunked_digests_match_one_shot() {
+        let dir = env::temp_dir().join(format!("rcr-test-engine-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut files: Vec<String> = Vec::new();
+        for (i, len) in [0usize, 1, 4095, 4096, 4097, 10_000, 65_537].iter().enumerate() {
+            let path = dir.join(format!("file{}", i));
+            let data: Vec<u8> = (0..*len).map(|i| (i * 7 % 253) as u8).collect();
+            fs::write(&path, &data).unwrap();
+            files.push(path.to_str().unwrap().to_string());
+        }
+
+        let algorithms = [HashAlgorithm::Crc32, HashAlgorithm::Md5, HashAlgorithm::Sha1, HashAlgorithm::Sha256];
+        for chunk_size in [3usize, 4096, 4099, 1 << 20].iter() {
+            let config = HashEngineConfig {
+                hashers: 3,
+                chunk_size: *chunk_size,
+                max_memory: 8 * *chunk_size,
+                ..Default::default()
+            };
+            let entries = hash_files(&files, &algorithms, &config);
+            assert_eq!(entries.len(), files.len());
+            for (entry, file) in entries.iter().zip(files.iter()) {
+                let expected = file_verification::get_checksum_from_file(file, &algorithms, false).unwrap();
+                assert_eq!(entry.path, *file);
+                assert_eq!(entry.length, expected.length);
+                assert_eq!(entry.digests, expected.digests, "{} in chunks of {}", file, chunk_size);
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
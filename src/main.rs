@@ -15,23 +15,36 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>
 
-mod utils;
-mod file_verification;
-mod par2_reader;
-mod sfv_reader;
+extern crate reverse_checksum_renamer;
+
+use reverse_checksum_renamer::file_verification;
+use reverse_checksum_renamer::par2_reader;
+use reverse_checksum_renamer::sfv_reader;
+use reverse_checksum_renamer::hash_cache;
+use reverse_checksum_renamer::hash_engine;
+use reverse_checksum_renamer::hash_backend;
+use reverse_checksum_renamer::concatenation;
+use reverse_checksum_renamer::carving;
+use reverse_checksum_renamer::throttle;
+use reverse_checksum_renamer::duplicates;
+use reverse_checksum_renamer::rename_journal;
 
 use std::fs;
+use std::io::{self, Write};
 use std::env;
 use std::process;
-use std::thread;
-use std::sync::{Arc};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use std::path::{Path, PathBuf};
 
 extern crate num_cpus;
+extern crate ctrlc;
 
 const STATE_FILE_FOUND: u8 = 0;
+const STATE_FILE_DAMAGED: u8 = 1;
 const IGNORE_EXTENSIONS: &[&str] = &[".nfo", ".txt", ".srr", ".sfv", ".par2"];
 
 const VERSION_MAJ: u32 = 0;
@@ -47,15 +60,32 @@ fn main() {
     
     let mut file_to_checksum: Option<String> = None;
     let mut file_to_decode: Option<String> = None;
+    let mut show_details = false;
+    let mut output_json = false;
 
     let mut do_fix_misnamed_catalog_files = false;
     let mut do_show_usage = false;
 
     let mut group_into_subdirectory = false;
     let mut only_complete_sets = false;
+    let mut accept_damaged = false;
+    let mut allow_repairable = false;
+    let mut trust_16k = false;
+    let mut trust_names = false;
+    let mut split_concatenated = false;
+    let mut join_pieces = false;
+    let mut carve = false;
+    let mut extra_algorithms: Vec<file_verification::HashAlgorithm> = Vec::new();
+    let mut use_hash_cache = true;
+    let mut resume = false;
 
-    let mut parallel = false;
     let mut dop: Option<usize> = None;
+    let mut max_memory = hash_engine::DEFAULT_MAX_MEMORY;
+    let mut idle = false;
+    let mut duplicate_mode = duplicates::DuplicateMode::Copy;
+    let mut refuse_weak_matches = false;
+    let mut explain = false;
+    let mut shared_policy = SharedPolicy::Copy;
 
     let mut verbose = false;
     let mut dry_run = false;
@@ -84,6 +114,12 @@ fn main() {
                 file_to_decode = Some(args[i+1].to_string());
                 skip = 1;
 
+            } else if args[i] == "--details" {
+                show_details = true;
+
+            } else if args[i] == "--json" {
+                output_json = true;
+
             } else if args[i] == "--checksum-file" {
                 assert_or_die_if_missing_par(&args, i);
                 file_to_checksum = Some(args[i+1].to_string());
@@ -96,6 +132,63 @@ fn main() {
             } else if args[i] == "-c" {
                 only_complete_sets = true;
 
+            } else if args[i] == "--allow-repairable" || args[i] == "-r" {
+                allow_repairable = true;
+
+            } else if args[i] == "--accept-damaged" {
+                accept_damaged = true;
+
+            } else if args[i] == "--split-concatenated" {
+                split_concatenated = true;
+
+            } else if args[i] == "--join-pieces" {
+                join_pieces = true;
+
+            } else if args[i] == "--carve" {
+                carve = true;
+
+            } else if args[i] == "--duplicates" {
+                assert_or_die_if_missing_par(&args, i);
+                match duplicates::DuplicateMode::from_name(&args[i+1]) {
+                    Some(v) => duplicate_mode = v,
+                    None => die(&format!("Unknown duplicate mode '{}'", args[i+1]), 1),
+                }
+                skip = 1;
+
+            } else if args[i] == "--shared" {
+                assert_or_die_if_missing_par(&args, i);
+                match SharedPolicy::from_name(&args[i+1]) {
+                    Some(v) => shared_policy = v,
+                    None => die(&format!("Unknown policy for shared files '{}'", args[i+1]), 1),
+                }
+                skip = 1;
+
+            } else if args[i] == "--refuse-weak-matches" {
+                refuse_weak_matches = true;
+
+            } else if args[i] == "--trust-16k" {
+                trust_16k = true;
+
+            } else if args[i] == "--trust-names" {
+                trust_names = true;
+
+            } else if args[i] == "--hash" {
+                assert_or_die_if_missing_par(&args, i);
+                match file_verification::HashAlgorithm::from_name(&args[i+1]) {
+                    Some(v) => extra_algorithms.push(v),
+                    None => die(&format!("Unknown hash algorithm '{}'", args[i+1]), 1),
+                }
+                skip = 1;
+
+            } else if args[i] == "--no-cache" {
+                use_hash_cache = false;
+
+            } else if args[i] == "--resume" {
+                resume = true;
+
+            } else if args[i] == "--explain" {
+                explain = true;
+
             } else if args[i] == "-g" {
                 group_into_subdirectory = true;
 
@@ -109,7 +202,28 @@ fn main() {
                 assert_or_die_if_missing_par(&args, i);
                 dop = Some(args[i+1].to_string().parse::<usize>().unwrap());
                 skip = 1;
-                parallel = (dop.unwrap() > 1) || (dop.unwrap() == 0);
+
+            } else if args[i] == "--no-accelerated-hashing" {
+                hash_backend::set_use_fallback(true);
+
+            } else if args[i] == "--max-memory" {
+                assert_or_die_if_missing_par(&args, i);
+                match args[i+1].parse::<usize>() {
+                    Ok(v) if v > 0 => max_memory = v * 1024 * 1024,
+                    _ => die(&format!("Invalid value for parameter '{}'", args[i]), 1),
+                }
+                skip = 1;
+
+            } else if args[i] == "--max-read-rate" {
+                assert_or_die_if_missing_par(&args, i);
+                match args[i+1].parse::<f64>() {
+                    Ok(v) if v > 0.0 => throttle::set_max_read_rate((v * 1_000_000.0) as u64),
+                    _ => die(&format!("Invalid value for parameter '{}'", args[i]), 1),
+                }
+                skip = 1;
+
+            } else if args[i] == "--idle" {
+                idle = true;
 
             } else if args[i] == "--help" {
                 do_show_usage = true;
@@ -124,6 +238,15 @@ fn main() {
     if verbose {
         println!("reverse-checksum-renamer V{}.{}", VERSION_MAJ, VERSION_MIN);
         par2_reader::set_verbose(verbose);
+        println!("Hashing backends: {}", hash_backend::describe());
+    }
+
+    if idle {
+        if let Err(ref _e) = throttle::set_idle_priority() {
+            println!("Could not set the idle priority: {}", _e);
+        } else if verbose {
+            println!("Running with idle I/O and CPU priority");
+        }
     }
 
     if do_show_usage {
@@ -132,14 +255,56 @@ fn main() {
         println!("  -o  output directory");
         println!("  -p  show referenced files in par2 or sfv file");
         println!("      (--show-catalog)");
+        println!("  --details");
+        println!("      with -p show all packets of a PAR2 file or all lines of a SFV file");
+        println!("  --json");
+        println!("      with -p --details print the details as JSON");
         println!("  -f  find PAR2/SFV files and rename them");
         println!("      (--fix-catalog-files)");
         println!("  -c  only complete sets");
+        println!("  -r  with -c also process PAR2 sets having enough recovery blocks to be repaired");
+        println!("      (--allow-repairable)");
+        println!("  --accept-damaged");
+        println!("      rename files matching most PAR2 slices of an entry even if damaged");
+        println!("  --split-concatenated");
+        println!("      split files which are concatenations of catalog entries into these entries");
+        println!("  --join-pieces");
+        println!("      join files which are pieces of a catalog entry into this entry");
+        println!("  --carve");
+        println!("      extract catalog entries found within larger files, e.g. disk images");
+        println!("  --duplicates <mode>");
+        println!("      create entries having the same content as another one by copy (default), hardlink or reflink");
+        println!("  --shared <policy>");
+        println!("      files matching entries of several catalogs are copied to each set (copy, default),");
+        println!("      moved to a complete set (complete) or to the set chosen when asked (ask)");
+        println!("  --refuse-weak-matches");
+        println!("      do not rename files matching an entry by CRC32 or name only, without a size to compare");
+        println!("  --trust-16k");
+        println!("      match PAR2 entries by length and MD5 of the first 16k without hashing the whole file");
+        println!("  --trust-names");
+        println!("      match files already having the name and size of an entry without reading them,");
+        println!("      or only the name for SFV entries without sizes");
+        println!("  --hash <algorithm>");
+        println!("      also calculate crc32, md5, sha1, sha256 or sha512 of the files, even if no catalog uses it");
+        println!("  --no-cache");
+        println!("      do not use the hash cache of previous runs");
+        println!("  --resume");
+        println!("      only hash the files left by an interrupted run");
+        println!("  --explain");
+        println!("      list for every entry the files considered and why they did not match");
         println!("  -g  group into subdirectories");
         println!("  -v  verbose");
         println!("  -d  dry run");
         println!("  --degree-of-parallelism <number>");
         println!("      maximum concurrent threads to calculate checksumes of files (0 is number of cores)");
+        println!("  --max-memory <MiB>");
+        println!("      maximum memory for read buffers while calculating checksums (default 256)");
+        println!("  --no-accelerated-hashing");
+        println!("      calculate CRC32 and MD5 with the fallback implementations");
+        println!("  --max-read-rate <MB/s>");
+        println!("      limit the rate all files are read with");
+        println!("  --idle");
+        println!("      run with idle I/O and CPU priority, so other workloads are not slowed down");
         println!("  --checksum-file <file>");
         println!("      print checksums of a file");
         
@@ -162,13 +327,25 @@ fn main() {
     if file_to_checksum.is_some() {
         let filepath = file_to_checksum.unwrap();
         println!("Calculating checksum of '{}' ...", filepath);
-        let checksum_of_file = file_verification::get_checksum_from_file(&filepath, true).unwrap();
-        println!("CRC32: {:x}", checksum_of_file.checksum_crc32.unwrap());
-        println!("MD5:   {}", checksum_of_file.checksum_md5_as_str());
+        let checksum_of_file = file_verification::get_checksum_from_file(&filepath, file_verification::ALL_ALGORITHMS, true).unwrap();
+        for algorithm in file_verification::ALL_ALGORITHMS {
+            println!("{:<7} {}", format!("{}:", algorithm), checksum_of_file.digest_as_str(*algorithm));
+        }
         println!("");
     }
 
-    if file_to_decode.is_some() {
+    if file_to_decode.is_some() && show_details {
+        let filepath = file_to_decode.unwrap();
+
+        if let Some(details) = par2_reader::read_par2_details(&filepath).ok().filter(|d| d.valid) {
+            if output_json { println!("{}", details.to_json()); } else { print!("{}", details.to_text()); }
+        } else if let Some(details) = sfv_reader::read_sfv_details(&filepath).ok().filter(|d| d.valid) {
+            if output_json { println!("{}", details.to_json()); } else { print!("{}", details.to_text()); }
+        } else {
+            println!("Not a valid catalog file");
+        }
+
+    } else if file_to_decode.is_some() {
         let filepath = file_to_decode.unwrap();
 
         #[allow(unused_assignments)]
@@ -213,20 +390,25 @@ fn main() {
             let src_type = file_verification::get_source_type_by_filename(&catalog_file_path);
             if src_type.is_some() {
                 
-                let catalog_opt = match src_type.unwrap() {
-                    file_verification::SourceTypes::SFV  => sfv_reader::read_sfv(&catalog_file_path).ok(),
-                    file_verification::SourceTypes::PAR2 => par2_reader::read_par2(&catalog_file_path).ok(),
+                let catalog_res = match src_type.unwrap() {
+                    file_verification::SourceTypes::SFV  => sfv_reader::read_sfv(&catalog_file_path),
+                    file_verification::SourceTypes::PAR2 => par2_reader::read_par2(&catalog_file_path),
                 };
+                if let Err(ref _e) = catalog_res {
+                    println!("Could not read {}: {}", &catalog_file_path, _e);
+                }
+                let catalog_opt = catalog_res.ok();
 
                 if catalog_opt.is_some() {
                     let catalog = catalog_opt.unwrap();
+                    for warning in &catalog.warnings {
+                        println!("Warning, {} is damaged: {}", catalog_file_path, warning);
+                    }
                     println!("{:?} entries found in '{}':", catalog.entries.len(), catalog_file_path);
                     let mut i = 0;
                     for e in &catalog.entries {
                         i += 1;
-                        let str_crc32 = e.checksum_crc32.map_or_else(|| "".to_string(), |v| format!("{:x}", v));
-                        let str_md5   = e.checksum_md5.map_or_else(|| "".to_string(),|v| utils::byte_array_to_hex(&v));
-                        println!("[{}] '{}' crc32:{} md5:{}", i, e.filename, str_crc32, str_md5);
+                        println!("[{}] '{}' {}", i, e.filename, e.digests_as_str());
                     }
                     println!("");
                     source_catalogs.push(catalog);
@@ -237,6 +419,16 @@ fn main() {
         }
     }
 
+    let num_catalog_files = source_catalogs.len();
+    let source_catalogs = par2_reader::merge_recovery_sets(source_catalogs);
+    if source_catalogs.len() < num_catalog_files {
+        for catalog in source_catalogs.iter().filter(|c| !c.volume_files.is_empty()) {
+            println!("Merged {} PAR2 files of one recovery set into {:?} having {} entries", 
+                catalog.volume_files.len() + 1, catalog.source_file, catalog.entries.len());
+        }
+        println!("");
+    }
+
     if source_file_path.is_some() && source_catalogs.len() > 0 {
         let mut paths_ok = true;
 
@@ -251,31 +443,325 @@ fn main() {
         }
 
         if paths_ok {
-            let mut existing_checksums;
-            if parallel {
-                existing_checksums = parallel_get_checksums_from_path(&source_file_path.unwrap(), dop);
+            let source_file_path = source_file_path.unwrap();
+            let checkpoint_path = hash_cache::checkpoint_path(Path::new(&source_file_path));
+            // Journals are written in the directory files are renamed in, with -g one per catalog
+            let mut journal_dirs: Vec<PathBuf> = vec![PathBuf::from(&source_file_path)];
+            for catalog in &source_catalogs {
+                let destination = get_destination_path(destination_file_path.as_ref().unwrap(), catalog, group_into_subdirectory);
+                if !journal_dirs.contains(&destination) {
+                    journal_dirs.push(destination);
+                }
+            }
+            for dir in &journal_dirs {
+                recover_temporary_names(dir, dry_run);
+            }
+            let journal_paths: Vec<PathBuf> = journal_dirs.iter().map(|d| rename_journal::journal_path(d)).collect();
+            let mut existing_files = get_files_from_path(&source_file_path).unwrap();
+            existing_files.retain(|f| *f != checkpoint_path && !journal_paths.contains(f));
+            let mut existing_checksums: Vec<file_verification::ChecksumEntry> = Vec::new();
+
+            if let Some(sizes) = get_size_index(&source_catalogs) {
+                let num_files = existing_files.len();
+                let (candidates, others) = get_size_candidates(existing_files, &sizes);
+                println!("{} of {} files are candidates by size", candidates.len(), num_files);
+                if !others.is_empty() {
+                    println!("No size candidate (not hashed):");
+                    for csf in &others {
+                        println!("  {} ({} bytes)", csf.path, csf.length.unwrap_or(0));
+                    }
+                }
+                existing_checksums.extend(others);
+                existing_files = candidates;
+            }
+
+            if can_use_16k_prepass(&source_catalogs) {
+                let num_files = existing_files.len();
+                let (candidates, others) = get_16k_prepass_candidates(&existing_files, &source_catalogs);
+                println!("{} of {} files are candidates by length and MD5 of the first 16k", candidates.len(), num_files);
+                existing_checksums.extend(others);
+
+                if trust_16k {
+                    existing_checksums.extend(candidates);
+                    existing_files = Vec::new();
+                } else {
+                    existing_files = candidates.iter().map(|c| PathBuf::from(&c.path)).collect();
+                }
+            }
+
+            let algorithms = get_required_algorithms(&source_catalogs, &extra_algorithms);
+            if verbose {
+                let names: Vec<String> = algorithms.iter().map(|a| a.to_string()).collect();
+                println!("Calculating {} of the files", names.join(", "));
+            }
+
+            let mut cache = match hash_cache::default_cache_path() {
+                Some(ref path) if use_hash_cache => Some(hash_cache::HashCache::load(path)),
+                _ => None,
+            };
+            let mut file_keys: HashMap<String, hash_cache::FileKey> = HashMap::new();
+            if let Some(ref mut cache) = cache {
+                let num_files = existing_files.len();
+                let (cached, uncached, keys) = get_cached_checksums(existing_files, &algorithms, cache);
+                println!("{} of {} files found in the hash cache", cached.len(), num_files);
+                existing_checksums.extend(cached);
+                existing_files = uncached;
+                file_keys = keys;
+            }
+
+            if resume {
+                let mut resumed = hash_cache::HashCache::load(&checkpoint_path);
+                let num_files = existing_files.len();
+                let (done, left, keys) = get_cached_checksums(existing_files, &algorithms, &mut resumed);
+                println!("{} of {} files found in the checkpoint of an interrupted run", done.len(), num_files);
+                existing_checksums.extend(done);
+                existing_files = left;
+                file_keys.extend(keys);
+            }
+
+            let num_files = existing_files.len();
+            let (named, others) = get_named_candidates(existing_files, &source_catalogs, trust_names, trust_16k);
+            if !named.is_empty() {
+                println!("{} of {} files already have the name of an entry, not hashed", named.len(), num_files);
+            }
+            let trusted_names: HashSet<String> = named.iter()
+                .filter(|e| e.first_16k_md5.is_none())
+                .map(|e| e.path.clone())
+                .collect();
+            existing_checksums.extend(named);
+            existing_files = others;
+
+            // Keys are taken before hashing, so a file changed meanwhile is not checkpointed
+            for file in &existing_files {
+                let path = String::from(file.as_path().to_str().unwrap());
+                if let Entry::Vacant(v) = file_keys.entry(path) {
+                    if let Some(key) = hash_cache::get_file_key(v.key()) {
+                        v.insert(key);
+                    }
+                }
+            }
+            let mut checkpoint = if existing_files.is_empty() {
+                None
             } else {
-                existing_checksums = get_checksums_from_path(&source_file_path.unwrap());
+                match hash_cache::Checkpoint::open(&checkpoint_path, resume) {
+                    Ok(v) => Some(v),
+                    Err(_e) => {
+                        println!("Could not write the checkpoint {:?}: {}", checkpoint_path, _e);
+                        None
+                    }
+                }
+            };
+
+            let engine_config = hash_engine::HashEngineConfig {
+                hashers: match dop {
+                    Some(0) => num_cpus::get(),
+                    Some(v) => v,
+                    None => 1,
+                },
+                max_memory,
+                verbose,
+                ..Default::default()
+            };
+
+            // Ctrl-C while hashing stops reading, the files finished so far are kept
+            let hashing = Arc::new(AtomicBool::new(true));
+            {
+                let hashing = hashing.clone();
+                let cancel = engine_config.cancel.clone();
+                let result = ctrlc::set_handler(move || {
+                    if hashing.load(Ordering::SeqCst) && !cancel.load(Ordering::SeqCst) {
+                        println!("Interrupted, finishing the files being hashed (press Ctrl-C again to quit at once) ...");
+                        cancel.store(true, Ordering::SeqCst);
+                    } else {
+                        process::exit(130);
+                    }
+                });
+                if let Err(ref _e) = result {
+                    println!("Could not handle Ctrl-C: {}", _e);
+                }
+            }
+
+            let num_files = existing_files.len();
+            let hashed_checksums = get_checksums_from_files(existing_files, &algorithms, &engine_config, |csf| {
+                if let (Some(checkpoint), Some(key)) = (checkpoint.as_mut(), file_keys.get(&csf.path)) {
+                    if let Err(ref _e) = checkpoint.append(key, &csf.digests) {
+                        println!("Could not write the checkpoint {:?}: {}", checkpoint_path, _e);
+                    }
+                }
+            });
+            hashing.store(false, Ordering::SeqCst);
+            let interrupted = engine_config.cancel.load(Ordering::SeqCst);
+
+            if let Some(ref mut cache) = cache {
+                for csf in &hashed_checksums {
+                    if let Some(key) = file_keys.get(&csf.path) {
+                        cache.insert(key, &csf.digests);
+                    }
+                }
+                let pruned = cache.prune();
+                if verbose { println!("{} entries in the hash cache, {} pruned", cache.len(), pruned); }
+                if let Err(ref _e) = cache.save() {
+                    println!("Could not save the hash cache: {}", _e);
+                }
+            }
+
+            if interrupted {
+                println!("Interrupted after hashing {} of {} files. Run again with --resume to hash the others.", hashed_checksums.len(), num_files);
+                process::exit(130);
+            }
+            if hashed_checksums.len() < num_files {
+                println!("{} of {} files could not be read and are not matched", num_files - hashed_checksums.len(), num_files);
+            }
+            if checkpoint.is_some() {
+                drop(checkpoint);
+                if let Err(ref _e) = fs::remove_file(&checkpoint_path) {
+                    println!("Could not remove the checkpoint {:?}: {}", checkpoint_path, _e);
+                }
             }
+            existing_checksums.extend(hashed_checksums);
             
             let destination_file_path = destination_file_path.unwrap();
 
+            // All catalogs are matched before any file is moved, so files matching entries
+            // of several catalogs are found regardless of the order of the catalogs
+            let mut plans: Vec<CatalogPlan> = Vec::new();
             for mut catalog in source_catalogs {
 
-                let recommendations = get_repair_recommendations(&mut existing_checksums, &mut catalog.entries);
+                let destination = get_destination_path(&destination_file_path, &catalog, group_into_subdirectory);
+                let repair = get_repair_recommendations(&mut existing_checksums, &mut catalog.entries, &destination, &trusted_names, refuse_weak_matches);
+                let mut renames = repair.renames;
+                let duplicates = repair.duplicates;
+                let mut refused = repair.refused;
+                let splits = if split_concatenated {
+                    get_split_recommendations(&mut existing_checksums, &mut catalog.entries, refuse_weak_matches, &mut refused)
+                } else {
+                    Vec::new()
+                };
+                let joins = if join_pieces {
+                    get_join_recommendations(&mut existing_checksums, &mut catalog.entries, refuse_weak_matches, &mut refused)
+                } else {
+                    Vec::new()
+                };
+                let carves = if carve {
+                    get_carve_recommendations(&mut existing_checksums, &mut catalog.entries, refuse_weak_matches, &mut refused)
+                } else {
+                    Vec::new()
+                };
+                let damaged = get_damaged_file_recommendations(&mut existing_checksums, &mut catalog.entries, accept_damaged, &engine_config);
+                let damaged = if accept_damaged {
+                    renames.extend(damaged);
+                    Vec::new()
+                } else {
+                    damaged
+                };
+                plans.push(CatalogPlan {
+                    destination,
+                    catalog,
+                    renames,
+                    shared: Vec::new(),
+                    duplicates,
+                    refused,
+                    collisions: repair.collisions,
+                    damaged,
+                    splits,
+                    joins,
+                    carves,
+                });
+            }
+
+            let moved_to = resolve_shared_files(&mut plans, shared_policy);
+
+            for plan in plans {
+                let CatalogPlan { mut catalog, destination: final_destination_path, renames: recommendations, shared,
+                    duplicates, refused, collisions, damaged, splits, joins, carves } = plan;
+
+                // Explained once all recommendations are final, i.e. damaged files are
+                // accepted and shared files are resolved
+                if explain {
+                    let matches = CatalogMatches {
+                        renames: &recommendations,
+                        shared: &shared,
+                        moved_to: &moved_to,
+                        duplicates: &duplicates,
+                        refused: &refused,
+                        damaged: &damaged,
+                        splits: &splits,
+                        joins: &joins,
+                        carves: &carves,
+                    };
+                    println!();
+                    println!("Explanation for {}:", catalog.source_file);
+                    explain_matches(&existing_checksums, &catalog.entries, &matches);
+                }
+
                 println!("");
                 println!("Recommendations for {}:", catalog.source_file);
+                if !damaged.is_empty() {
+                    println!("Possibly damaged files (use --accept-damaged to rename them):");
+                    for recommendation in &damaged {
+                        println!("[-] {}", recommendation);
+                    }
+                }
+                if !collisions.is_empty() {
+                    println!("CRC32 collisions, files of different size or content match the same entry:");
+                    for collision in &collisions {
+                        println!("[!] {}", collision);
+                    }
+                }
+                if !refused.is_empty() {
+                    println!("Weak matches, not renamed because of --refuse-weak-matches:");
+                    for recommendation in &refused {
+                        println!("[-] {}", recommendation);
+                    }
+                }
+                let confidences: Vec<file_verification::MatchConfidence> = recommendations.iter().chain(shared.iter()).map(|r| r.confidence)
+                    .chain(duplicates.iter().map(|d| d.confidence))
+                    .chain(splits.iter().chain(carves.iter()).map(|s| s.confidence))
+                    .chain(joins.iter().map(|j| j.confidence))
+                    .collect();
+                let weak = confidences.iter().filter(|c| c.is_weak()).count();
+                if weak > 0 {
+                    println!("{} of {} matches are by CRC32 or name only, use --refuse-weak-matches to skip them", weak, confidences.len());
+                }
                 let mut i = 0;
                 for recommendation in &recommendations {
                     i+=1;
-                    println!("[{}] {} -> {}", i, recommendation.source_file, recommendation.target_name);
+                    println!("[{}] {}", i, recommendation);
+                }
+                for recommendation in &shared {
+                    println!("[shared {}] {}", duplicate_mode, recommendation);
+                }
+                for duplicate in &duplicates {
+                    println!("[{}] {}", duplicate_mode, duplicate);
+                }
+                for split in &splits {
+                    println!("[split] {}", split);
+                }
+                for join in &joins {
+                    println!("[join] {}", join);
+                }
+                for carve in &carves {
+                    println!("[carve] {}", carve);
                 }
 
                 println!("");
                 update_catalog_file_status(&mut catalog);
                 if catalog_has_missing_files(&catalog) {
                     println!("Catalog {} has missing files!", catalog.source_file);
-                    if only_complete_sets {
+
+                    let repairable = match get_repair_capacity(&catalog) {
+                        Some((needed, available)) => {
+                            if needed <= available {
+                                println!("Catalog {} is repairable with {} of {} recovery blocks", catalog.source_file, needed, available);
+                            } else {
+                                println!("Catalog {} is not repairable, {} recovery blocks needed but only {} available", catalog.source_file, needed, available);
+                            }
+                            needed <= available
+                        },
+                        None => false,
+                    };
+
+                    if only_complete_sets && !(allow_repairable && repairable) {
                         println!("Will not process files.");
                         continue;
                     }
@@ -283,16 +769,8 @@ fn main() {
                     println!("Catalog {} is complete", catalog.source_file);
                 }
 
-                let catalog_path = Path::new(&catalog.source_file);
-                let mut final_destination_path = PathBuf::new();
-                final_destination_path.push(&destination_file_path);
-
                 if group_into_subdirectory {
-                    let mut catalog_filename = String::from(catalog_path.file_name().unwrap().to_str().unwrap());
-                    catalog_filename.push_str("_FILES");
-                    if verbose { println!("Subdirectory name {:?}", catalog_filename); }
-
-                    final_destination_path = final_destination_path.join(&catalog_filename);
+                    if verbose { println!("Subdirectory name {:?}", final_destination_path.file_name().unwrap()); }
 
                     if !final_destination_path.exists() {
                         if dry_run {
@@ -308,13 +786,25 @@ fn main() {
                     if verbose { println!("Will group into directory {:?}", final_destination_path); }
                 };
 
-                repair_filenames(&mut existing_checksums, &mut catalog.entries, &final_destination_path, dry_run, verbose);
-
-                // Move catalog file to destination                
-                let dst_catalog_path = final_destination_path.join(catalog_path.file_name().unwrap());
-                if catalog_path != dst_catalog_path {
-                    print!("{:?} -> {:?}", catalog_path, dst_catalog_path);
-                    std::fs::rename(catalog_path, dst_catalog_path).expect("Moving of catalog failed!");
+                repair_filenames(&recommendations, &final_destination_path, dry_run, verbose);
+                copy_shared_files(&shared, &moved_to, &final_destination_path, duplicate_mode, dry_run);
+                create_duplicates(&duplicates, &final_destination_path, duplicate_mode, dry_run);
+                split_files(&splits, &final_destination_path, dry_run);
+                join_files(&joins, &final_destination_path, dry_run);
+                carve_files(&carves, &final_destination_path, dry_run);
+
+                // Move catalog file and all volumes of its recovery set to destination
+                for catalog_file in catalog.get_source_files() {
+                    let catalog_path = Path::new(catalog_file);
+                    let dst_catalog_path = final_destination_path.join(catalog_path.file_name().unwrap());
+                    if catalog_path != dst_catalog_path {
+                        if dry_run {
+                            println!("[dry run] Will move {:?} to {:?}", catalog_path, dst_catalog_path);
+                        } else {
+                            println!("{:?} -> {:?}", catalog_path, dst_catalog_path);
+                            std::fs::rename(catalog_path, dst_catalog_path).expect("Moving of catalog failed!");
+                        }
+                    }
                 }
             }
         }
@@ -335,6 +825,293 @@ fn die(message: &str, exit_code: i32) {
 struct RenamingRecommendation {
     source_file: String,
     target_name: String,
+    bad_slices: Option<(usize, usize)>, // (bad, total) if the source is damaged
+    confidence: file_verification::MatchConfidence,
+}
+
+impl std::fmt::Display for RenamingRecommendation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} -> {}", self.source_file, self.target_name)?;
+        if let Some((bad, total)) = self.bad_slices {
+            write!(f, " (damaged: {} of {} slices bad)", bad, total)?;
+        } else {
+            write!(f, " ({})", self.confidence)?;
+        }
+        Ok(())
+    }
+}
+
+// Files matching an entry by CRC32, whose sizes show they are not the same
+struct Collision {
+    target_name: String,
+    crc32: String,
+    source_files: Vec<String>,
+}
+
+impl std::fmt::Display for Collision {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} (CRC32 {}) is matched by {}", self.target_name, self.crc32, self.source_files.join(", "))
+    }
+}
+
+// Files matched to the entries of a catalog
+struct RepairRecommendations {
+    renames: Vec<RenamingRecommendation>,
+    duplicates: Vec<DuplicateRecommendation>,
+    refused: Vec<RenamingRecommendation>, // weak matches with --refuse-weak-matches
+    collisions: Vec<Collision>,
+}
+
+// An entry having the same content as another one, created from the renamed file
+struct DuplicateRecommendation {
+    source_name: String,
+    target_name: String,
+    confidence: file_verification::MatchConfidence,
+}
+
+impl std::fmt::Display for DuplicateRecommendation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} -> {} ({})", self.source_name, self.target_name, self.confidence)
+    }
+}
+
+// A file made up of several catalog entries, or containing them (carving)
+struct SplitRecommendation {
+    source_file: String,
+    pieces: Vec<(u64, u64, String)>, // (offset, length, target name)
+    confidence: file_verification::MatchConfidence, // of the least reliable piece
+}
+
+impl std::fmt::Display for SplitRecommendation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let names: Vec<&str> = self.pieces.iter().map(|p| p.2.as_str()).collect();
+        write!(f, "{} -> {} ({})", self.source_file, names.join(" + "), self.confidence)
+    }
+}
+
+impl SplitRecommendation {
+    // Refused pieces are listed like weak renames
+    fn to_refused(&self) -> Vec<RenamingRecommendation> {
+        self.pieces.iter().map(|p| RenamingRecommendation {
+            source_file: format!("{} at offset {}", self.source_file, p.0),
+            target_name: p.2.clone(),
+            bad_slices: None,
+            confidence: self.confidence,
+        }).collect()
+    }
+}
+
+// Several files making up one catalog entry
+struct JoinRecommendation {
+    source_files: Vec<String>,
+    target_name: String,
+    confidence: file_verification::MatchConfidence,
+}
+
+impl std::fmt::Display for JoinRecommendation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} -> {} ({})", self.source_files.join(" + "), self.target_name, self.confidence)
+    }
+}
+
+// How files matching entries of several catalogs are resolved
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum SharedPolicy {
+    Copy,     // moved for one catalog, copied for the others
+    Complete, // moved for a complete catalog, the others miss the entry
+    Ask,
+}
+
+impl SharedPolicy {
+    fn from_name(name: &str) -> Option<SharedPolicy> {
+        match name.to_lowercase().as_str() {
+            "copy" => Some(SharedPolicy::Copy),
+            "complete" => Some(SharedPolicy::Complete),
+            "ask" => Some(SharedPolicy::Ask),
+            _ => None,
+        }
+    }
+}
+
+// Everything to be done for a catalog, planned before any file is moved
+struct CatalogPlan {
+    catalog: file_verification::ChecksumCatalog,
+    destination: PathBuf,
+    renames: Vec<RenamingRecommendation>,
+    shared: Vec<RenamingRecommendation>, // files moved for another catalog, copied for this one
+    duplicates: Vec<DuplicateRecommendation>,
+    refused: Vec<RenamingRecommendation>,
+    collisions: Vec<Collision>,
+    damaged: Vec<RenamingRecommendation>, // not renamed without --accept-damaged
+    splits: Vec<SplitRecommendation>,
+    joins: Vec<JoinRecommendation>,
+    carves: Vec<SplitRecommendation>,
+}
+
+impl CatalogPlan {
+    // The entry, and the duplicates created from it, are missing again
+    fn leave_out(&mut self, target_name: &str) {
+        let mut names = vec![target_name.to_string()];
+        self.duplicates.retain(|d| {
+            if d.source_name == target_name {
+                names.push(d.target_name.clone());
+                return false;
+            }
+            true
+        });
+        for entry in self.catalog.entries.iter_mut().filter(|e| names.contains(&e.filename)) {
+            entry.reset_state(STATE_FILE_FOUND);
+            entry.reset_state(STATE_FILE_DAMAGED);
+        }
+    }
+}
+
+// Everything found for the entries of a catalog, for --explain
+struct CatalogMatches<'a> {
+    renames: &'a [RenamingRecommendation],
+    shared: &'a [RenamingRecommendation],
+    moved_to: &'a HashMap<String, PathBuf>, // shared files moved to the set of another catalog
+    duplicates: &'a [DuplicateRecommendation],
+    refused: &'a [RenamingRecommendation],
+    damaged: &'a [RenamingRecommendation],
+    splits: &'a [SplitRecommendation],
+    joins: &'a [JoinRecommendation],
+    carves: &'a [SplitRecommendation],
+}
+
+impl<'a> CatalogMatches<'a> {
+    // How the entry was found, None if it is missing
+    fn get_outcome(&self, target_name: &str) -> Option<String> {
+        if let Some(r) = self.renames.iter().find(|r| r.target_name == target_name) {
+            return Some(match r.bad_slices {
+                Some((bad, total)) => format!("renamed from {} (damaged: {} of {} slices bad)", r.source_file, bad, total),
+                None => format!("renamed from {} ({})", r.source_file, r.confidence),
+            });
+        }
+        if let Some(r) = self.shared.iter().find(|r| r.target_name == target_name) {
+            return Some(format!("copied from {} ({})", r.source_file, r.confidence));
+        }
+        if let Some(d) = self.duplicates.iter().find(|d| d.target_name == target_name) {
+            return Some(format!("duplicate of {}", d.source_name));
+        }
+        if let Some(r) = self.refused.iter().find(|r| r.target_name == target_name) {
+            return Some(format!("weak match {} refused ({})", r.source_file, r.confidence));
+        }
+        if let Some(r) = self.damaged.iter().find(|r| r.target_name == target_name) {
+            return Some(format!("possibly damaged {}", r));
+        }
+        for (kind, list) in [("split", self.splits), ("carved", self.carves)].iter() {
+            for split in list.iter() {
+                if let Some(piece) = split.pieces.iter().find(|p| p.2 == target_name) {
+                    return Some(format!("{} out of {} at offset {}", kind, split.source_file, piece.0));
+                }
+            }
+        }
+        if let Some(j) = self.joins.iter().find(|j| j.target_name == target_name) {
+            return Some(format!("joined from {}", j.source_files.join(" + ")));
+        }
+        None
+    }
+
+    // The entries a file was used for
+    fn get_claims(&self, source_file: &str) -> Vec<String> {
+        let mut claims: Vec<String> = Vec::new();
+        claims.extend(self.renames.iter().chain(self.shared.iter()).filter(|r| r.source_file == source_file).map(|r| r.target_name.clone()));
+        for split in self.splits.iter().chain(self.carves.iter()).filter(|s| s.source_file == source_file) {
+            claims.extend(split.pieces.iter().map(|p| p.2.clone()));
+        }
+        claims.extend(self.joins.iter().filter(|j| j.source_files.iter().any(|f| f == source_file)).map(|j| j.target_name.clone()));
+        claims
+    }
+}
+
+fn size_as_str(length: Option<u64>) -> String {
+    match length {
+        Some(v) => format!("{} bytes", v),
+        None => "size unknown".to_string(),
+    }
+}
+
+// e.g. "crc32 matches, md5 differs", only for the digests both have
+fn compare_digests(tcs: &file_verification::ChecksumEntry, ecs: &file_verification::ChecksumEntry) -> String {
+    if !ecs.has_digests() {
+        return if ecs.first_16k_md5.is_some() && tcs.first_16k_md5.is_some() && ecs.first_16k_md5 != tcs.first_16k_md5 {
+            "first 16k differ".to_string()
+        } else {
+            "not hashed".to_string()
+        };
+    }
+    let compared: Vec<String> = tcs.digests.iter()
+        .filter_map(|(a, v)| ecs.get_digest(*a).map(|w| format!("{} {}", a, if v == w { "matches" } else { "differs" })))
+        .collect();
+    if compared.is_empty() { "no common digest".to_string() } else { compared.join(", ") }
+}
+
+// For every entry the files having its size or matching any of its digests and why
+// they were not taken, then every file not used with the entry nearest in size
+fn explain_matches(existing_checksums: &[file_verification::ChecksumEntry], target_checksums: &[file_verification::ChecksumEntry], matches: &CatalogMatches) {
+    for tcs in target_checksums.iter().filter(|e| e.valid) {
+        println!("{} ({}, {})", tcs.filename, size_as_str(tcs.length), tcs.digests_as_str());
+        let outcome = matches.get_outcome(&tcs.filename);
+        println!("    {}", outcome.as_ref().map_or("missing", |v| v.as_str()));
+
+        for ecs in existing_checksums {
+            let same_size = tcs.length.is_some() && tcs.length == ecs.length;
+            let damaged = matches.damaged.iter().find(|r| r.source_file == ecs.path && r.target_name == tcs.filename);
+            if !same_size && !tcs.matches_digests(ecs) && damaged.is_none() {
+                continue;
+            }
+            let claims = matches.get_claims(&ecs.path);
+            let refused = matches.refused.iter().find(|r| r.target_name == tcs.filename);
+            if claims.contains(&tcs.filename) || refused.is_some_and(|r| r.source_file == ecs.path) {
+                continue;
+            }
+
+            let mut reasons: Vec<String> = Vec::new();
+            if let Some(confidence) = tcs.match_confidence(ecs) {
+                if refused.is_some() && confidence.is_weak() {
+                    reasons.push(format!("weak match refused ({})", confidence));
+                } else if let Some(path) = matches.moved_to.get(&ecs.path) {
+                    reasons.push(format!("matches ({}), moved to {:?} of another catalog", confidence, path));
+                } else if claims.is_empty() {
+                    reasons.push(format!("matches ({}), the entry has a file already", confidence));
+                } else {
+                    reasons.push(format!("already claimed by {}", claims.join(", ")));
+                }
+            } else if tcs.matches_digests(ecs) {
+                reasons.push(format!("size mismatch ({})", size_as_str(ecs.length)));
+            } else {
+                reasons.push(compare_digests(tcs, ecs));
+            }
+            if let Some(&(bad, total)) = damaged.and_then(|r| r.bad_slices.as_ref()) {
+                reasons.push(format!("damaged, {} of {} slices bad", bad, total));
+            }
+            println!("    - {}: {}", ecs.path, reasons.join(", "));
+        }
+    }
+
+    let unused: Vec<&file_verification::ChecksumEntry> = existing_checksums.iter()
+        .filter(|ecs| !ecs.has_state(STATE_FILE_FOUND) && matches.get_claims(&ecs.path).is_empty())
+        .collect();
+    if unused.is_empty() {
+        return;
+    }
+    println!("Files not matching any entry:");
+    for ecs in unused {
+        let nearest = target_checksums.iter()
+            .filter(|e| e.valid && e.length.is_some() && ecs.length.is_some())
+            .min_by_key(|e| (e.length.unwrap() as i64 - ecs.length.unwrap() as i64).abs());
+        match nearest {
+            Some(tcs) if tcs.length == ecs.length => {
+                println!("    {} ({}): same size as {}, {}", ecs.path, size_as_str(ecs.length), tcs.filename, compare_digests(tcs, ecs));
+            },
+            Some(tcs) => {
+                let difference = ecs.length.unwrap() as i64 - tcs.length.unwrap() as i64;
+                println!("    {} ({}): nearest is {} ({}, {:+} bytes), {}", ecs.path, size_as_str(ecs.length), tcs.filename, size_as_str(tcs.length), difference, compare_digests(tcs, ecs));
+            },
+            None => println!("    {} ({}): no entry of known size", ecs.path, size_as_str(ecs.length)),
+        }
+    }
 }
 
 fn fix_misnamed_catalog_files(path_s: &String, dry_run: bool, verbose: bool) -> u32 {
@@ -399,13 +1176,34 @@ fn catalog_has_missing_files(catalog: &file_verification::ChecksumCatalog) -> bo
                 continue;
             }
         }
-        if !entry.has_state(STATE_FILE_FOUND) {
+        if !entry.has_state(STATE_FILE_FOUND) || entry.has_state(STATE_FILE_DAMAGED) {
             return true;
         }
     }
     false
 }
 
+// Returns the number of recovery blocks needed to repair the missing and damaged
+// files of a PAR2 catalog and the number of recovery blocks available
+fn get_repair_capacity(catalog: &file_verification::ChecksumCatalog) -> Option<(u64, u64)> {
+    let slice_size = match catalog.slice_size {
+        Some(v) if v > 0 => v,
+        _ => return None,
+    };
+
+    // A damaged file only needs its bad slices, whether it is renamed or not
+    let mut needed: u64 = 0;
+    for entry in catalog.entries.iter().filter(|e| !e.has_state(STATE_FILE_FOUND) || e.has_state(STATE_FILE_DAMAGED)) {
+        needed += match (entry.bad_slices, entry.length) {
+            (Some(bad), _) => bad as u64,
+            (None, Some(length)) => length.div_ceil(slice_size),
+            (None, None) => entry.slice_checksums.len() as u64,
+        };
+    }
+
+    Some((needed, catalog.recovery_exponents.len() as u64))
+}
+
 fn update_catalog_file_status(catalog: &mut file_verification::ChecksumCatalog) {
     update_file_status(&mut catalog.entries);
 }
@@ -421,51 +1219,51 @@ fn update_file_status(entries: &mut Vec<file_verification::ChecksumEntry>) {
 }
 
 fn repair_filenames(
-        mut source_checksums: &mut Vec<file_verification::ChecksumEntry>, 
-        mut target_checksums: &mut Vec<file_verification::ChecksumEntry>, 
+        recommendations: &[RenamingRecommendation],
         destination_file_path: &Path, 
         dry_run: bool, verbose: bool,
         // only_complete_set: bool
         ) -> bool {
 
     let dest_path = Path::new(&destination_file_path);
+    let mut journal = rename_journal::RenameJournal::new(&rename_journal::journal_path(dest_path));
+    let mut paths = PlannedPaths::default();
 
-    let recommendations = get_repair_recommendations(&mut source_checksums, &mut target_checksums);
-    
-    let mut to_do:     Vec<&RenamingRecommendation> = Vec::new();
-    let mut push_back: Vec<&RenamingRecommendation> = Vec::new();
+    // The current path of each source, a temporary one while breaking a cycle
+    let mut to_do:     Vec<(PathBuf, &RenamingRecommendation)> = Vec::new();
+    let mut push_back: Vec<(PathBuf, &RenamingRecommendation)> = Vec::new();
+    let mut temporaries: Vec<(PathBuf, PathBuf)> = Vec::new(); // (temporary, original)
 
-    recommendations.iter().for_each(|x| to_do.push(&x));
+    recommendations.iter().for_each(|x| to_do.push((PathBuf::from(&x.source_file), x)));
 
     let mut rename_count = 1;
 
     while to_do.len() > 0 && rename_count > 0 {
         rename_count = 0;
 
-        for recommendation in &to_do {
+        for (src, recommendation) in to_do.drain(..) {
             if verbose {
                 println!("Recommend renaming '{}' to '{}'",
-                    recommendation.source_file, recommendation.target_name);
+                    src.to_str().unwrap(), recommendation.target_name);
             }
 
-            let src = Path::new(&recommendation.source_file);
             let dst = dest_path.join(&recommendation.target_name);
 
-            if dst.exists() {
-                if src == dst.as_path() {
+            if paths.exists(&dst) {
+                if src == dst {
                     println!("No need to rename {:?}", src.to_str().unwrap());
                 } else {
                     println!("Will not rename {:?} because it will overwrite {:?}! Will try later.",
                         src.to_str().unwrap(),
                         dst.as_path().to_str().unwrap());
-                    push_back.push(recommendation)
+                    push_back.push((src, recommendation))
                 }
             } else {
                 rename_count += 1;
                 if !dry_run {
                     println!("Renaming {:?} to {:?} ...", src.to_str(), dst.as_path().to_str());
                     if src.exists() {
-                        std::fs::rename(src, dst).expect("Renaming failed!");
+                        std::fs::rename(&src, &dst).expect("Renaming failed!");
                     } else {
                         println!("Not renaming {:?}. File not found!", src.to_str());
                     }
@@ -474,6 +1272,8 @@ fn repair_filenames(
                         src.to_str().unwrap(),
                         dst.as_path().to_str().unwrap());
                 }
+                paths.rename(&src, &dst);
+                temporaries.retain(|t| t.0 != src);
             }
         }
 
@@ -481,34 +1281,314 @@ fn repair_filenames(
             println!("Try to rename {:?} pushed back files.", push_back.len());
         }
 
-        to_do.clear();
         push_back.reverse(); // Important
-        push_back.iter().for_each(|x| to_do.push(&x));
+        to_do.append(&mut push_back);
 
         if rename_count == 0 && to_do.len() > 0 {
-            println!("Stuck in a renaming loop. {:?} files can not be renamed without having the same name! Abort!", to_do.len());
+            // Files taking each other's names form a cycle, which is broken by moving
+            // one of them to a temporary name. Other files in the way are not touched.
+            match find_rename_cycle(&to_do, dest_path) {
+                Some(index) => {
+                    let src = to_do[index].0.clone();
+                    let tmp = rename_journal::temporary_path_in(dest_path, &src);
+                    if dry_run {
+                        println!("[dry run] Will rename {:?} to {:?} to break a renaming loop!", src.to_str().unwrap(), tmp.to_str().unwrap());
+                    } else {
+                        if let Err(ref _e) = journal.record(&tmp, &src) {
+                            println!("Could not write the journal of temporary names: {}", _e);
+                            println!("Stuck in a renaming loop. {:?} files can not be renamed without having the same name! Abort!", to_do.len());
+                            break;
+                        }
+                        println!("Renaming {:?} to {:?} to break a renaming loop ...", src.to_str(), tmp.to_str());
+                        std::fs::rename(&src, &tmp).expect("Renaming failed!");
+                    }
+                    paths.rename(&src, &tmp);
+                    temporaries.push((tmp.clone(), src));
+                    to_do[index].0 = tmp;
+                    rename_count = 1;
+                },
+                None => {
+                    println!("Stuck. {:?} files can not be renamed without overwriting other files! Abort!", to_do.len());
+                },
+            }
+        }
+    }
+
+    // Only left if renaming failed, the files get their names back
+    if !dry_run {
+        for (tmp, original) in &temporaries {
+            restore_temporary_name(tmp, original, dry_run);
+        }
+        if let Err(ref _e) = journal.remove() {
+            println!("Could not remove the journal of temporary names: {}", _e);
         }
     }
     true
 }
 
-fn get_repair_recommendations(existing_checksums: &mut Vec<file_verification::ChecksumEntry>, target_checksums: &mut Vec<file_verification::ChecksumEntry>) -> Vec<RenamingRecommendation> {
-    let mut recommendations: Vec<RenamingRecommendation> = Vec::new();
+// Returns a file whose target is taken by another file to be renamed, and so on,
+// until the target is the name of the first file
+fn find_rename_cycle(to_do: &[(PathBuf, &RenamingRecommendation)], dest_path: &Path) -> Option<usize> {
+    let sources: HashMap<&PathBuf, usize> = to_do.iter().enumerate().map(|(i, t)| (&t.0, i)).collect();
+    for start in 0..to_do.len() {
+        let mut current = start;
+        for _ in 0..to_do.len() {
+            match sources.get(&dest_path.join(&to_do[current].1.target_name)) {
+                Some(&next) if next == start => return Some(start),
+                Some(&next) => current = next,
+                None => break,
+            }
+        }
+    }
+    None
+}
 
-    for ecs in existing_checksums.iter_mut() {
-        for tcs in target_checksums.iter_mut() {
+fn restore_temporary_name(tmp: &Path, original: &Path, dry_run: bool) {
+    if original.exists() {
+        println!("Could not rename {:?} back to {:?}, the file exists!", tmp, original);
+    } else if dry_run {
+        println!("[dry run] Will rename {:?} back to {:?}!", tmp, original);
+    } else {
+        println!("Renaming {:?} back to {:?} ...", tmp, original);
+        if let Err(ref _e) = fs::rename(tmp, original) {
+            println!("Could not rename {:?} back to {:?}: {}", tmp, original, _e);
+        }
+    }
+}
+
+// Temporary names left by an interrupted run are renamed back, the files are then
+// matched again like all others
+fn recover_temporary_names(dir: &Path, dry_run: bool) {
+    let path = rename_journal::journal_path(dir);
+    if !path.exists() {
+        return;
+    }
+    let renames = match rename_journal::read_journal(&path) {
+        Ok(v) => v,
+        Err(_e) => {
+            println!("Could not read the journal {:?}: {}", path, _e);
+            return;
+        }
+    };
+    println!("Found the journal of an interrupted run in {:?}", dir);
+    for (tmp, original) in renames.iter().filter(|r| r.0.exists()) {
+        restore_temporary_name(tmp, original, dry_run);
+    }
+    if !dry_run {
+        if let Err(ref _e) = fs::remove_file(&path) {
+            println!("Could not remove the journal {:?}: {}", path, _e);
+        }
+    }
+}
+
+// In a dry run nothing is renamed, so the paths renamed from and to are tracked
+#[derive(Default)]
+struct PlannedPaths {
+    vacated: HashSet<PathBuf>,
+    occupied: HashSet<PathBuf>,
+}
+
+impl PlannedPaths {
+    fn exists(&self, path: &Path) -> bool {
+        self.occupied.contains(path) || (path.exists() && !self.vacated.contains(path))
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) {
+        self.occupied.remove(from);
+        self.vacated.insert(from.to_path_buf());
+        self.vacated.remove(to);
+        self.occupied.insert(to.to_path_buf());
+    }
+}
+
+// Each entry gets one of the files matching it, preferably the one already having its
+// name, and not one having the name of another entry. If there are more entries than files
+// of the same content, the other entries become duplicates of the first one. Files
+// matching by CRC32 only are weak matches, refused if requested. Files trusted by
+// their name (--trust-names) are not hashed and matched by name and size, or by name
+// only for entries without a size, which is weak as well.
+fn get_repair_recommendations(existing_checksums: &mut Vec<file_verification::ChecksumEntry>, target_checksums: &mut Vec<file_verification::ChecksumEntry>,
+        destination_file_path: &Path, trusted_names: &HashSet<String>, refuse_weak: bool) -> RepairRecommendations {
+    let mut result = RepairRecommendations {
+        renames: Vec::new(),
+        duplicates: Vec::new(),
+        refused: Vec::new(),
+        collisions: Vec::new(),
+    };
+    let mut renamed: HashMap<usize, String> = HashMap::new(); // file -> entry it is renamed to
+    let entry_paths: HashSet<PathBuf> = target_checksums.iter()
+        .filter(|t| t.valid)
+        .map(|t| destination_file_path.join(&t.filename))
+        .collect();
+
+    for tcs in target_checksums.iter_mut() {
+        if !tcs.valid {
+            continue;
+        }
+
+        let matching: Vec<(usize, file_verification::MatchConfidence)> = (0..existing_checksums.len())
+            .filter_map(|i| {
+                let ecs = &existing_checksums[i];
+
+                // Only files not hashed completely (--trust-16k) are matched by their first 16k
+                if !ecs.has_digests() && ecs.first_16k_md5.is_some()
+                    && tcs.first_16k_md5 == ecs.first_16k_md5 && tcs.length == ecs.length {
+                    return Some((i, file_verification::MatchConfidence::First16kAndSize));
+                }
+                if trusted_names.contains(&ecs.path) {
+                    if !is_similar_name(&ecs.filename, &tcs.filename) {
+                        return None;
+                    } else if tcs.length == ecs.length {
+                        return Some((i, file_verification::MatchConfidence::NameAndSize));
+                    } else if tcs.length.is_none() {
+                        return Some((i, file_verification::MatchConfidence::NameOnly));
+                    }
+                    return None;
+                }
+                tcs.match_confidence(ecs).map(|c| (i, c))
+            })
+            .collect();
+        if matching.is_empty() {
+            continue;
+        }
 
-            if tcs.valid {
-                let crc32_matches = tcs.checksum_crc32.is_some() && ecs.checksum_crc32.is_some() && tcs.checksum_crc32 == ecs.checksum_crc32;
-                let md5_matches   = tcs.checksum_md5.is_some()   && ecs.checksum_md5.is_some()   && tcs.checksum_md5 == ecs.checksum_md5;
+        // Files of different sizes or content can't all be this entry
+        let first = &existing_checksums[matching[0].0];
+        let differing = matching.iter()
+            .filter(|m| m.1 != file_verification::MatchConfidence::StrongHash)
+            .map(|m| &existing_checksums[m.0])
+            .any(|ecs| ecs.length != first.length || (ecs.digests.keys().any(|a| a.is_strong()) && !ecs.is_same_content(first)));
+        if differing {
+            result.collisions.push(Collision {
+                target_name: tcs.filename.clone(),
+                crc32: tcs.digest_as_str(file_verification::HashAlgorithm::Crc32),
+                source_files: matching.iter().map(|m| existing_checksums[m.0].path.clone()).collect(),
+            });
+        }
 
-                if crc32_matches || md5_matches {
-                    recommendations.push(RenamingRecommendation {
-                        source_file: ecs.path.clone(),
+        let target_path = destination_file_path.join(&tcs.filename);
+        let unclaimed: Vec<&(usize, file_verification::MatchConfidence)> = matching.iter()
+            .filter(|m| !renamed.contains_key(&m.0))
+            .collect();
+        let chosen = unclaimed.iter().find(|m| Path::new(&existing_checksums[m.0].path) == target_path)
+            .or_else(|| unclaimed.iter().find(|m| !entry_paths.contains(Path::new(&existing_checksums[m.0].path))))
+            .or_else(|| unclaimed.first())
+            .map(|m| **m);
+        match chosen {
+            Some((index, confidence)) => {
+                let ecs = &mut existing_checksums[index];
+                let recommendation = RenamingRecommendation {
+                    source_file: ecs.path.clone(),
+                    target_name: tcs.filename.clone(),
+                    bad_slices: None,
+                    confidence,
+                };
+                if refuse_weak && confidence.is_weak() {
+                    result.refused.push(recommendation);
+                    continue;
+                }
+                result.renames.push(recommendation);
+                ecs.set_state(STATE_FILE_FOUND);
+                renamed.insert(index, tcs.filename.clone());
+            },
+            None => {
+                // All matching files are renamed, the most reliable one is the source
+                let &(index, confidence) = matching.iter().min_by_key(|m| m.1).unwrap();
+                if refuse_weak && confidence.is_weak() {
+                    result.refused.push(RenamingRecommendation {
+                        source_file: existing_checksums[index].path.clone(),
                         target_name: tcs.filename.clone(),
+                        bad_slices: None,
+                        confidence,
                     });
+                    continue;
+                }
+                result.duplicates.push(DuplicateRecommendation {
+                    source_name: renamed[&index].clone(),
+                    target_name: tcs.filename.clone(),
+                    confidence,
+                });
+            },
+        }
+        tcs.set_state(STATE_FILE_FOUND);
+    }
+    result
+}
+
+// Files whose full checksum does not match are scored by the PAR2 slice checksums
+// of the remaining entries. A file matching most slices of an entry is damaged. Only
+// files of about the length of an entry, at most one slice shorter or longer, are read.
+fn get_damaged_file_recommendations(
+        existing_checksums: &mut [file_verification::ChecksumEntry],
+        target_checksums: &mut [file_verification::ChecksumEntry],
+        claim: bool, config: &hash_engine::HashEngineConfig) -> Vec<RenamingRecommendation> {
+
+    let mut recommendations: Vec<RenamingRecommendation> = Vec::new();
+
+    let is_candidate = |tcs: &file_verification::ChecksumEntry, ecs: &file_verification::ChecksumEntry, slice_size: u64| {
+        !ecs.has_state(STATE_FILE_FOUND) && match (ecs.length, tcs.length) {
+            (Some(file_length), Some(entry_length)) => file_length.abs_diff(entry_length) <= slice_size,
+            _ => false,
+        }
+    };
+    let is_damaged_candidate = |tcs: &file_verification::ChecksumEntry| {
+        tcs.valid && !tcs.has_state(STATE_FILE_FOUND) && !tcs.slice_checksums.is_empty()
+    };
+
+    let mut keys: Vec<(String, u64)> = Vec::new();
+    for tcs in target_checksums.iter().filter(|t| is_damaged_candidate(t)) {
+        let slice_size = match tcs.slice_size {
+            Some(v) => v,
+            None => continue,
+        };
+        for ecs in existing_checksums.iter().filter(|e| is_candidate(tcs, e, slice_size)) {
+            let key = (ecs.path.clone(), slice_size);
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+    }
+    let slices = hash_engine::slice_checksums_of_files(&keys, config);
+    let file_slices: HashMap<(String, u64), Vec<file_verification::SliceChecksum>> = keys.into_iter().zip(slices).collect();
+
+    for tcs in target_checksums.iter_mut() {
+        let slice_size = match tcs.slice_size {
+            Some(v) => v,
+            None => continue,
+        };
+        if !is_damaged_candidate(tcs) {
+            continue;
+        }
+
+        let mut best: Option<(usize, usize)> = None; // (index, matching slices)
+
+        for (idx, ecs) in existing_checksums.iter().enumerate() {
+            if !is_candidate(tcs, ecs, slice_size) {
+                continue;
+            }
+
+            let key = (ecs.path.clone(), slice_size);
+            let matching = file_verification::count_matching_slices(&file_slices[&key], &tcs.slice_checksums);
+            if matching > best.map_or(0, |b| b.1) {
+                best = Some((idx, matching));
+            }
+        }
+
+        if let Some((idx, matching)) = best {
+            let total = tcs.slice_checksums.len();
+            if matching * 2 > total {
+                let ecs = &mut existing_checksums[idx];
+                recommendations.push(RenamingRecommendation {
+                    source_file: ecs.path.clone(),
+                    target_name: tcs.filename.clone(),
+                    bad_slices: Some((total - matching, total)),
+                    confidence: file_verification::MatchConfidence::Slices,
+                });
+                tcs.bad_slices = Some(total - matching);
+                if claim {
                     ecs.set_state(STATE_FILE_FOUND);
                     tcs.set_state(STATE_FILE_FOUND);
+                    tcs.set_state(STATE_FILE_DAMAGED);
                 }
             }
         }
@@ -516,72 +1596,640 @@ fn get_repair_recommendations(existing_checksums: &mut Vec<file_verification::Ch
     recommendations
 }
 
-fn get_checksums_from_path(source_file_path: &String) -> Vec<file_verification::ChecksumEntry> {
-    let existing_files = get_files_from_path(&source_file_path).unwrap();
-    let num_files = existing_files.len();
-    let mut existing_checksums: Vec<file_verification::ChecksumEntry> = Vec::new();
+// Lengths of all catalog entries, or None if any entry has no length and thus
+// every file could match it
+fn get_size_index(catalogs: &[file_verification::ChecksumCatalog]) -> Option<HashSet<u64>> {
+    let mut sizes: HashSet<u64> = HashSet::new();
+    for e in catalogs.iter().flat_map(|c| c.entries.iter()).filter(|e| e.valid) {
+        sizes.insert(e.length?);
+    }
+    Some(sizes)
+}
+
+// Files whose length matches no entry are not hashed. They are still returned without
+// digests, so they can be found as damaged files.
+fn get_size_candidates(existing_files: Vec<PathBuf>, sizes: &HashSet<u64>) -> (Vec<PathBuf>, Vec<file_verification::ChecksumEntry>) {
+    let mut candidates: Vec<PathBuf> = Vec::new();
+    let mut others: Vec<file_verification::ChecksumEntry> = Vec::new();
 
-    for (i, existing_file) in existing_files.iter().enumerate() {
+    for existing_file in existing_files {
         let path = String::from(existing_file.as_path().to_str().unwrap());
-        println!("[{} of {}] Checking file '{}' ...", i+1, num_files, path);
-        let csf = file_verification::get_checksum_from_file(&path, true).unwrap();
-        existing_checksums.push(csf);
+        match fs::metadata(&path) {
+            Ok(m) if !sizes.contains(&m.len()) => others.push(file_verification::ChecksumEntry::from_path(&path, m.len())),
+            _ => candidates.push(existing_file),
+        }
+    }
+    (candidates, others)
+}
+
+// Files not matching any entry are checked for being a concatenation of the
+// remaining entries having a length. Weak matches are refused if requested.
+fn get_split_recommendations(
+        existing_checksums: &mut [file_verification::ChecksumEntry],
+        target_checksums: &mut [file_verification::ChecksumEntry],
+        refuse_weak: bool, refused: &mut Vec<RenamingRecommendation>) -> Vec<SplitRecommendation> {
+
+    let mut splits: Vec<SplitRecommendation> = Vec::new();
+
+    for ecs in existing_checksums.iter_mut() {
+        if ecs.has_state(STATE_FILE_FOUND) {
+            continue;
+        }
+        let length = match ecs.length.or_else(|| fs::metadata(&ecs.path).ok().map(|m| m.len())) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let open_entries: Vec<usize> = (0..target_checksums.len())
+            .filter(|i| target_checksums[*i].valid && !target_checksums[*i].has_state(STATE_FILE_FOUND))
+            .collect();
+        let pieces = {
+            let entries: Vec<&file_verification::ChecksumEntry> = open_entries.iter().map(|i| &target_checksums[*i]).collect();
+            match concatenation::find_concatenation(&ecs.path, length, &entries) {
+                Ok(v) => v,
+                Err(_e) => {
+                    println!("Could not read {:?}: {}", ecs.path, _e);
+                    None
+                }
+            }
+        };
+
+        if let Some(pieces) = pieces {
+            let split = get_split(&ecs.path, &pieces, &open_entries, target_checksums);
+            if refuse_weak && split.confidence.is_weak() {
+                refused.extend(split.to_refused());
+                continue;
+            }
+            for piece in &pieces {
+                target_checksums[open_entries[piece.entry]].set_state(STATE_FILE_FOUND);
+            }
+            ecs.set_state(STATE_FILE_FOUND);
+            splits.push(split);
+        }
     }
-    existing_checksums
+    splits
 }
 
-// Alternative parallel libs: 
-// https://docs.rs/threadpool/1.7.1/threadpool/
-// https://docs.rs/rayon/1.3.0/rayon/
+// Pieces of a file matching the given open entries
+fn get_split(source_file: &str, pieces: &[concatenation::Piece], open_entries: &[usize],
+        target_checksums: &[file_verification::ChecksumEntry]) -> SplitRecommendation {
+    let entries: Vec<&file_verification::ChecksumEntry> = pieces.iter().map(|p| &target_checksums[open_entries[p.entry]]).collect();
+    SplitRecommendation {
+        source_file: source_file.to_string(),
+        pieces: pieces.iter().zip(entries.iter()).map(|(p, e)| (p.offset, p.length, e.filename.clone())).collect(),
+        confidence: entries.iter().map(|e| e.verified_confidence()).max().unwrap_or(file_verification::MatchConfidence::StrongHash),
+    }
+}
 
-fn parallel_get_checksums_from_path(source_file_path: &String, dop: Option<usize>) -> Vec<file_verification::ChecksumEntry> {
-    let existing_files = get_files_from_path(&source_file_path).unwrap();
-    let num_files = existing_files.len();
-    let mut existing_checksums: Vec<file_verification::ChecksumEntry> = Vec::new();
+// The output directory, with -g the subdirectory of the catalog
+fn get_destination_path(destination_file_path: &str, catalog: &file_verification::ChecksumCatalog, group_into_subdirectory: bool) -> PathBuf {
+    let mut path = PathBuf::from(destination_file_path);
+    if group_into_subdirectory {
+        let mut catalog_filename = String::from(Path::new(&catalog.source_file).file_name().unwrap().to_str().unwrap());
+        catalog_filename.push_str("_FILES");
+        path = path.join(&catalog_filename);
+    }
+    path
+}
 
-    let mut handles: Vec<thread::JoinHandle<Vec<file_verification::ChecksumEntry>>> = Vec::new();
-    let next_file_index = Arc::new(AtomicUsize::new(0));
-    let count_files_finished = Arc::new(AtomicUsize::new(0));
-    let arc_existing_files = Arc::new(existing_files);
+// A file renamed for entries of several catalogs can only be moved for one of them.
+// Without a plan the first catalog would move it and the others would not find it.
+// Returns where the shared files are moved to, the copies for the other catalogs
+// are made from there if they are executed later.
+fn resolve_shared_files(plans: &mut [CatalogPlan], policy: SharedPolicy) -> HashMap<String, PathBuf> {
+    let mut moved_to: HashMap<String, PathBuf> = HashMap::new();
+
+    let mut sources: Vec<String> = Vec::new();
+    let mut sharers: HashMap<String, Vec<usize>> = HashMap::new(); // file -> plans renaming it
+    for (index, plan) in plans.iter().enumerate() {
+        for recommendation in &plan.renames {
+            let indexes = sharers.entry(recommendation.source_file.clone()).or_default();
+            if indexes.is_empty() {
+                sources.push(recommendation.source_file.clone());
+            }
+            indexes.push(index);
+        }
+    }
+    sources.retain(|s| sharers[s].len() > 1);
+    if sources.is_empty() {
+        return moved_to;
+    }
 
-    let dop = match dop {
-        Some(ref _v) => dop.unwrap(),
-        None => num_cpus::get()
+    let complete: Vec<bool> = plans.iter().map(|p| !catalog_has_missing_files(&p.catalog)).collect();
+    let get_target = |plan: &CatalogPlan, source: &str| -> String {
+        plan.renames.iter().find(|r| r.source_file == source).unwrap().target_name.clone()
     };
 
-    for _i in 0..dop {
-        let next_file_index = next_file_index.clone();
-        let count_files_finished = count_files_finished.clone();
-        let arc_existing_files = arc_existing_files.clone();
-
-        let handle = thread::spawn(move || {
-            let mut checksums: Vec<file_verification::ChecksumEntry> = Vec::new();
-            loop {
-                let file_idx = next_file_index.fetch_add(1, Ordering::Relaxed);
-                if file_idx >= arc_existing_files.len() {
-                    break;
-                }
-                let existing_file = arc_existing_files.get(file_idx).unwrap();
-                let path = String::from(existing_file.as_path().to_str().unwrap());
-                println!("Checking file '{}' ...", path);
-                let csf = file_verification::get_checksum_from_file(&path, false).unwrap();
-                let cff = count_files_finished.fetch_add(1, Ordering::Relaxed);
-                println!("[{} of {}] Finished checking file '{}' ...", cff+1, num_files, path);
-                checksums.push(csf);
-            }
-            checksums
+    println!();
+    println!("Files matching entries of several catalogs:");
+    for source in &sources {
+        let indexes = &sharers[source];
+        let names: Vec<String> = indexes.iter()
+            .map(|i| format!("{} of {}", get_target(&plans[*i], source), plans[*i].catalog.source_file))
+            .collect();
+        println!("  {}: {}", source, names.join(", "));
+
+        // None copies the file, it is moved for the catalog it already has the name of or the first one
+        let choice = match policy {
+            SharedPolicy::Copy => None,
+            SharedPolicy::Complete => Some(*indexes.iter().find(|i| complete[**i]).unwrap_or(&indexes[0])),
+            SharedPolicy::Ask => ask_shared_owner(source, indexes, plans),
+        };
+        let owner = choice.unwrap_or_else(|| {
+            *indexes.iter()
+                .find(|i| plans[**i].destination.join(get_target(&plans[**i], source)) == Path::new(source))
+                .unwrap_or(&indexes[0])
         });
-        handles.push(handle);        
+
+        let target_name = get_target(&plans[owner], source);
+        println!("    moved to {} of {}{}", target_name, plans[owner].catalog.source_file,
+            if complete[owner] { " (complete)" } else { "" });
+        moved_to.insert(source.clone(), plans[owner].destination.join(&target_name));
+
+        for index in indexes.iter().filter(|i| **i != owner) {
+            let plan = &mut plans[*index];
+            let position = plan.renames.iter().position(|r| r.source_file == *source).unwrap();
+            let recommendation = plan.renames.remove(position);
+            if choice.is_none() {
+                println!("    copied to {} of {}", recommendation.target_name, plan.catalog.source_file);
+                plan.shared.push(recommendation);
+            } else {
+                println!("    left out of {}", plan.catalog.source_file);
+                plan.leave_out(&recommendation.target_name);
+            }
+        }
+    }
+    moved_to
+}
+
+// Returns the plan the file is moved for, or None to copy it to all of them
+fn ask_shared_owner(source: &str, indexes: &[usize], plans: &[CatalogPlan]) -> Option<usize> {
+    for (n, index) in indexes.iter().enumerate() {
+        println!("    [{}] {}", n + 1, plans[*index].catalog.source_file);
+    }
+    loop {
+        print!("    Move {} for catalog [1-{}] or [c]opy it to all? ", source, indexes.len());
+        let _ = io::stdout().flush();
+        let mut answer = String::new();
+        match io::stdin().read_line(&mut answer) {
+            Ok(0) | Err(_) => {
+                println!("c");
+                return None;
+            },
+            _ => {},
+        }
+        let answer = answer.trim();
+        if answer.is_empty() || answer.eq_ignore_ascii_case("c") {
+            return None;
+        }
+        if let Ok(n) = answer.parse::<usize>() {
+            if (1..=indexes.len()).contains(&n) {
+                return Some(indexes[n - 1]);
+            }
+        }
+    }
+}
+
+// Files moved for another catalog are copied from where they are, before or after moving
+fn copy_shared_files(shared: &[RenamingRecommendation], moved_to: &HashMap<String, PathBuf>, destination_file_path: &Path,
+        mode: duplicates::DuplicateMode, dry_run: bool) {
+    for recommendation in shared {
+        let mut source = PathBuf::from(&recommendation.source_file);
+        if !source.exists() {
+            if let Some(path) = moved_to.get(&recommendation.source_file) {
+                source = path.clone();
+            }
+        }
+        let target = destination_file_path.join(&recommendation.target_name);
+        if target.exists() {
+            if source == target {
+                println!("No need to {} {:?}", mode, source);
+            } else {
+                println!("Will not {} {:?} because it will overwrite {:?}!", mode, source, target);
+            }
+            continue;
+        }
+
+        if dry_run {
+            println!("[dry run] Will {} {:?} to {:?}!", mode, source, target);
+            continue;
+        }
+
+        println!("Duplicating {:?} to {:?} ({}) ...", source, target, mode);
+        if let Err(ref _e) = duplicates::create_duplicate(&source, &target, mode) {
+            println!("Could not {} {:?} to {:?}: {}", mode, source, target, _e);
+        }
     }
+}
+
+// Run after renaming, the renamed file is the source of its duplicates
+fn create_duplicates(duplicates: &[DuplicateRecommendation], destination_file_path: &Path, mode: duplicates::DuplicateMode, dry_run: bool) {
+    for duplicate in duplicates {
+        let source = destination_file_path.join(&duplicate.source_name);
+        let target = destination_file_path.join(&duplicate.target_name);
+        if target.exists() {
+            println!("Will not {} {:?} because it will overwrite {:?}!", mode, source, target);
+            continue;
+        }
 
-    for handle in handles {
-        let checksums_result = handle.join().unwrap();
-        for csf in checksums_result {
-            existing_checksums.push(csf);
+        if dry_run {
+            println!("[dry run] Will {} {:?} to {:?}!", mode, source, target);
+            continue;
+        }
+
+        println!("Duplicating {:?} to {:?} ({}) ...", source, target, mode);
+        if let Err(ref _e) = duplicates::create_duplicate(&source, &target, mode) {
+            println!("Could not {} {:?} to {:?}: {}", mode, source, target, _e);
         }
     }
+}
+
+// The concatenated file is kept, its pieces are written as new files
+fn split_files(splits: &[SplitRecommendation], destination_file_path: &Path, dry_run: bool) {
+    for split in splits {
+        let pieces: Vec<(u64, u64, PathBuf)> = split.pieces.iter()
+            .map(|p| (p.0, p.1, destination_file_path.join(&p.2)))
+            .collect();
+
+        if let Some(existing) = pieces.iter().find(|p| p.2.exists()) {
+            println!("Will not split {:?} because it will overwrite {:?}!", split.source_file, existing.2);
+            continue;
+        }
+
+        if dry_run {
+            println!("[dry run] Will split {:?} into {} files!", split.source_file, pieces.len());
+            continue;
+        }
 
-    existing_checksums
+        println!("Splitting {:?} into {} files ...", split.source_file, pieces.len());
+        let targets: Vec<(u64, u64, &Path)> = pieces.iter().map(|p| (p.0, p.1, p.2.as_path())).collect();
+        if let Err(ref _e) = concatenation::split_file(&split.source_file, &targets) {
+            println!("Could not split {:?}: {}", split.source_file, _e);
+        }
+    }
+}
+
+// Missing entries are checked for being made up of several files not matching any entry.
+// Weak matches are refused if requested.
+fn get_join_recommendations(
+        existing_checksums: &mut [file_verification::ChecksumEntry],
+        target_checksums: &mut [file_verification::ChecksumEntry],
+        refuse_weak: bool, refused: &mut Vec<RenamingRecommendation>) -> Vec<JoinRecommendation> {
+
+    let mut joins: Vec<JoinRecommendation> = Vec::new();
+
+    for tcs in target_checksums.iter_mut() {
+        if !tcs.valid || tcs.has_state(STATE_FILE_FOUND) {
+            continue;
+        }
+
+        let open_files: Vec<usize> = (0..existing_checksums.len())
+            .filter(|i| !existing_checksums[*i].has_state(STATE_FILE_FOUND))
+            .collect();
+        let order = {
+            let inputs: Vec<concatenation::Input> = open_files.iter()
+                .map(|i| &existing_checksums[*i])
+                .map(|ecs| concatenation::Input {
+                    path: &ecs.path,
+                    length: ecs.length.or_else(|| fs::metadata(&ecs.path).ok().map(|m| m.len())).unwrap_or(0),
+                    crc32: ecs.get_crc32(),
+                })
+                .collect();
+            match concatenation::find_pieces(tcs, &inputs) {
+                Ok(v) => v,
+                Err(_e) => {
+                    println!("Could not read pieces of {:?}: {}", tcs.filename, _e);
+                    None
+                }
+            }
+        };
+
+        if let Some(order) = order {
+            let join = JoinRecommendation {
+                source_files: order.iter().map(|i| existing_checksums[open_files[*i]].path.clone()).collect(),
+                target_name: tcs.filename.clone(),
+                confidence: tcs.verified_confidence(),
+            };
+            if refuse_weak && join.confidence.is_weak() {
+                refused.push(RenamingRecommendation {
+                    source_file: join.source_files.join(" + "),
+                    target_name: join.target_name,
+                    bad_slices: None,
+                    confidence: join.confidence,
+                });
+                continue;
+            }
+            for index in order {
+                existing_checksums[open_files[index]].set_state(STATE_FILE_FOUND);
+            }
+            tcs.set_state(STATE_FILE_FOUND);
+            joins.push(join);
+        }
+    }
+    joins
+}
+
+// The pieces are kept, the joined file is written as a new file
+fn join_files(joins: &[JoinRecommendation], destination_file_path: &Path, dry_run: bool) {
+    for join in joins {
+        let target = destination_file_path.join(&join.target_name);
+        if target.exists() {
+            println!("Will not join {} files because it will overwrite {:?}!", join.source_files.len(), target);
+            continue;
+        }
+
+        if dry_run {
+            println!("[dry run] Will join {} files into {:?}!", join.source_files.len(), target);
+            continue;
+        }
+
+        println!("Joining {} files into {:?} ...", join.source_files.len(), target);
+        let pieces: Vec<&str> = join.source_files.iter().map(|p| p.as_str()).collect();
+        if let Err(ref _e) = concatenation::join_files(&pieces, &target) {
+            println!("Could not join into {:?}: {}", target, _e);
+        }
+    }
+}
+
+// Files not matching any entry are searched for the remaining entries at any offset.
+// Weak matches are refused if requested.
+fn get_carve_recommendations(
+        existing_checksums: &mut [file_verification::ChecksumEntry],
+        target_checksums: &mut [file_verification::ChecksumEntry],
+        refuse_weak: bool, refused: &mut Vec<RenamingRecommendation>) -> Vec<SplitRecommendation> {
+
+    let mut carves: Vec<SplitRecommendation> = Vec::new();
+
+    for ecs in existing_checksums.iter_mut() {
+        if ecs.has_state(STATE_FILE_FOUND) {
+            continue;
+        }
+        let length = match ecs.length.or_else(|| fs::metadata(&ecs.path).ok().map(|m| m.len())) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let open_entries: Vec<usize> = (0..target_checksums.len())
+            .filter(|i| target_checksums[*i].valid && !target_checksums[*i].has_state(STATE_FILE_FOUND))
+            .collect();
+        if open_entries.is_empty() {
+            break;
+        }
+        println!("Carving {:?} ...", ecs.path);
+        let pieces = {
+            let entries: Vec<&file_verification::ChecksumEntry> = open_entries.iter().map(|i| &target_checksums[*i]).collect();
+            match carving::find_entries(&ecs.path, length, &entries) {
+                Ok(v) => v,
+                Err(_e) => {
+                    println!("Could not read {:?}: {}", ecs.path, _e);
+                    Vec::new()
+                }
+            }
+        };
+
+        if !pieces.is_empty() {
+            let carve = get_split(&ecs.path, &pieces, &open_entries, target_checksums);
+            if refuse_weak && carve.confidence.is_weak() {
+                refused.extend(carve.to_refused());
+                continue;
+            }
+            for piece in &pieces {
+                target_checksums[open_entries[piece.entry]].set_state(STATE_FILE_FOUND);
+            }
+            // Not claimed, it may contain entries of further catalogs
+            carves.push(carve);
+        }
+    }
+    carves
+}
+
+// The searched file is kept, the entries found are written as new files
+fn carve_files(carves: &[SplitRecommendation], destination_file_path: &Path, dry_run: bool) {
+    for carve in carves {
+        for &(offset, length, ref name) in &carve.pieces {
+            let target = destination_file_path.join(name);
+            if target.exists() {
+                println!("Will not carve {:?} out of {:?} because it will overwrite {:?}!", name, carve.source_file, target);
+                continue;
+            }
+
+            if dry_run {
+                println!("[dry run] Will carve {:?} at offset {} out of {:?}!", target, offset, carve.source_file);
+                continue;
+            }
+
+            println!("Carving {:?} at offset {} out of {:?} ...", target, offset, carve.source_file);
+            if let Err(ref _e) = concatenation::split_file(&carve.source_file, &[(offset, length, target.as_path())]) {
+                println!("Could not carve {:?}: {}", target, _e);
+            }
+        }
+    }
+}
+
+// The pre-pass is only useful if every entry can be checked by length and first 16k (PAR2)
+fn can_use_16k_prepass(catalogs: &[file_verification::ChecksumCatalog]) -> bool {
+    catalogs.iter()
+        .flat_map(|c| c.entries.iter())
+        .filter(|e| e.valid)
+        .all(|e| e.length.is_some() && e.first_16k_md5.is_some())
+}
+
+// Returns the files having the length and the MD5 of the first 16k of any catalog entry
+// and all other files. The latter will never match by their full checksum.
+fn get_16k_prepass_candidates(
+        existing_files: &[PathBuf],
+        catalogs: &[file_verification::ChecksumCatalog])
+        -> (Vec<file_verification::ChecksumEntry>, Vec<file_verification::ChecksumEntry>) {
+
+    let mut candidates: Vec<file_verification::ChecksumEntry> = Vec::new();
+    let mut others: Vec<file_verification::ChecksumEntry> = Vec::new();
+
+    let entries: Vec<&file_verification::ChecksumEntry> = catalogs.iter()
+        .flat_map(|c| c.entries.iter())
+        .filter(|e| e.valid)
+        .collect();
+
+    for existing_file in existing_files {
+        let path = String::from(existing_file.as_path().to_str().unwrap());
+
+        let length = match fs::metadata(&path) {
+            Ok(m) => m.len(),
+            Err(_e) => {
+                println!("Could not read {:?}: {}", path, _e);
+                continue;
+            }
+        };
+
+        if !entries.iter().any(|e| e.length == Some(length)) {
+            others.push(file_verification::ChecksumEntry::from_path(&path, length));
+            continue;
+        }
+
+        let csf = match file_verification::get_first_16k_md5_from_file(&path) {
+            Ok(v) => v,
+            Err(_e) => {
+                println!("Could not read {:?}: {}", path, _e);
+                continue;
+            }
+        };
+
+        if entries.iter().any(|e| e.length == csf.length && e.first_16k_md5 == csf.first_16k_md5) {
+            candidates.push(csf);
+        } else {
+            others.push(csf);
+        }
+    }
+
+    (candidates, others)
+}
+
+// Files already having the name and size of an entry are not hashed with --trust-names,
+// or with --trust-16k if they have the MD5 of the first 16k of a PAR2 entry. With
+// --trust-names a file may also have the name of a SFV entry without a size. All other
+// files are hashed. Returns the named files and all other files.
+fn get_named_candidates(
+        existing_files: Vec<PathBuf>,
+        catalogs: &[file_verification::ChecksumCatalog],
+        trust_names: bool,
+        trust_16k: bool)
+        -> (Vec<file_verification::ChecksumEntry>, Vec<PathBuf>) {
+
+    if !trust_names && !trust_16k {
+        return (Vec::new(), existing_files);
+    }
+
+    let mut named: Vec<file_verification::ChecksumEntry> = Vec::new();
+    let mut others: Vec<PathBuf> = Vec::new();
+
+    let entries: Vec<&file_verification::ChecksumEntry> = catalogs.iter()
+        .flat_map(|c| c.entries.iter())
+        .filter(|e| e.valid && (e.length.is_some() || trust_names))
+        .collect();
+
+    for existing_file in existing_files {
+        let path = String::from(existing_file.as_path().to_str().unwrap());
+        let file_name = match existing_file.file_name().and_then(|n| n.to_str()) {
+            Some(v) => v,
+            None => {
+                others.push(existing_file);
+                continue;
+            }
+        };
+        let length = match fs::metadata(&path) {
+            Ok(m) => m.len(),
+            Err(_e) => {
+                others.push(existing_file);
+                continue;
+            }
+        };
+
+        let similar: Vec<&&file_verification::ChecksumEntry> = entries.iter()
+            .filter(|e| e.length.unwrap_or(length) == length && is_similar_name(file_name, &e.filename))
+            .collect();
+        if similar.is_empty() {
+            others.push(existing_file);
+        } else if trust_names {
+            named.push(file_verification::ChecksumEntry::from_path(&path, length));
+        } else if similar.iter().any(|e| e.first_16k_md5.is_some()) {
+            match file_verification::get_first_16k_md5_from_file(&path) {
+                Ok(csf) if similar.iter().any(|e| e.first_16k_md5 == csf.first_16k_md5) => named.push(csf),
+                Ok(_) => others.push(existing_file),
+                Err(_e) => {
+                    println!("Could not read {:?}: {}", path, _e);
+                    others.push(existing_file);
+                }
+            }
+        } else {
+            others.push(existing_file);
+        }
+    }
+
+    (named, others)
+}
+
+// A file name the catalog name may have been changed to: other case, a suffix added
+// by downloading it twice ('name.rar.1', 'name (1).rar') or a missing extension ('name')
+fn is_similar_name(file_name: &str, entry_name: &str) -> bool {
+    let file_name = file_name.to_lowercase();
+    let entry_name = entry_name.to_lowercase();
+    if file_name == entry_name || strip_copy_suffix(&file_name) == entry_name {
+        return true;
+    }
+    let has_extension = file_name.rfind('.').is_some_and(|pos| pos > 0);
+    match entry_name.rfind('.') {
+        Some(pos) if pos > 0 && !has_extension => file_name == entry_name[..pos],
+        _ => false,
+    }
+}
+
+fn strip_copy_suffix(name: &str) -> String {
+    // 'name.rar.1', but not 'name.001'
+    if let Some(pos) = name.rfind('.') {
+        let suffix = &name[pos + 1..];
+        if !suffix.is_empty() && !suffix.starts_with('0') && suffix.bytes().all(|b| b.is_ascii_digit()) {
+            return name[..pos].to_string();
+        }
+    }
+
+    // 'name (1).rar'
+    let (base, extension) = match name.rfind('.') {
+        Some(pos) if pos > 0 => name.split_at(pos),
+        _ => (name, ""),
+    };
+    if let Some(open) = base.rfind('(') {
+        let number = &base[open + 1..];
+        if number.len() > 1 && number.ends_with(')') && number[..number.len() - 1].bytes().all(|b| b.is_ascii_digit()) {
+            return format!("{}{}", base[..open].trim_end(), extension);
+        }
+    }
+    name.to_string()
+}
+
+// Files unchanged since a previous run take their digests from the cache. The keys
+// of the other files are taken before hashing, so a file changed meanwhile is not cached.
+fn get_cached_checksums(files: Vec<PathBuf>, algorithms: &[file_verification::HashAlgorithm], cache: &mut hash_cache::HashCache)
+    -> (Vec<file_verification::ChecksumEntry>, Vec<PathBuf>, HashMap<String, hash_cache::FileKey>) {
+    let mut cached: Vec<file_verification::ChecksumEntry> = Vec::new();
+    let mut uncached: Vec<PathBuf> = Vec::new();
+    let mut keys: HashMap<String, hash_cache::FileKey> = HashMap::new();
+
+    for file in files {
+        let path = String::from(file.as_path().to_str().unwrap());
+        let key = match hash_cache::get_file_key(&path) {
+            Some(v) => v,
+            None => { uncached.push(file); continue; }
+        };
+
+        match cache.get(&key, algorithms) {
+            Some(digests) => {
+                let mut csf = file_verification::ChecksumEntry::from_path(&path, key.size);
+                csf.digests = digests;
+                cached.push(csf);
+            },
+            None => {
+                keys.insert(path, key);
+                uncached.push(file);
+            },
+        }
+    }
+    (cached, uncached, keys)
+}
+
+// Only the digests used by any of the catalogs are calculated, plus the requested ones
+fn get_required_algorithms(catalogs: &[file_verification::ChecksumCatalog], extra: &[file_verification::HashAlgorithm]) -> Vec<file_verification::HashAlgorithm> {
+    let mut algorithms: BTreeSet<file_verification::HashAlgorithm> = extra.iter().cloned().collect();
+    for catalog in catalogs {
+        algorithms.extend(catalog.get_algorithms());
+    }
+    algorithms.into_iter().collect()
+}
+
+fn get_checksums_from_files<F>(existing_files: Vec<PathBuf>, algorithms: &[file_verification::HashAlgorithm], config: &hash_engine::HashEngineConfig, on_finished: F)
+    -> Vec<file_verification::ChecksumEntry> where F: FnMut(&file_verification::ChecksumEntry) {
+    let paths: Vec<String> = existing_files.iter()
+        .map(|f| String::from(f.as_path().to_str().unwrap()))
+        .collect();
+    hash_engine::hash_files_with(&paths, algorithms, config, on_finished)
 }
 
 fn get_files_from_path(path_s: &String) -> Result<Vec<PathBuf>, std::io::Error> {
@@ -600,3 +2248,127 @@ fn get_files_from_path(path_s: &String) -> Result<Vec<PathBuf>, std::io::Error>
         .map(|x| x.map(|p| p.path()))
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rcr-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn rename_to(source: &str, target: &str) -> RenamingRecommendation {
+        RenamingRecommendation {
+            source_file: source.to_string(),
+            target_name: target.to_string(),
+            bad_slices: None,
+            confidence: file_verification::MatchConfidence::StrongHash,
+        }
+    }
+
+    #[test]
+    fn finds_a_cycle_of_two_files() {
+        let (a, b) = (rename_to("/d/a", "b"), rename_to("/d/b", "a"));
+        let to_do = vec![(PathBuf::from("/d/a"), &a), (PathBuf::from("/d/b"), &b)];
+        assert_eq!(find_rename_cycle(&to_do, Path::new("/d")), Some(0));
+    }
+
+    #[test]
+    fn finds_a_cycle_of_three_files() {
+        // x is only waiting for a, which is part of the cycle
+        let (x, a, b, c) = (rename_to("/d/x", "a"), rename_to("/d/a", "b"), rename_to("/d/b", "c"), rename_to("/d/c", "a"));
+        let to_do = vec![(PathBuf::from("/d/x"), &x), (PathBuf::from("/d/a"), &a),
+            (PathBuf::from("/d/b"), &b), (PathBuf::from("/d/c"), &c)];
+        assert_eq!(find_rename_cycle(&to_do, Path::new("/d")), Some(1));
+    }
+
+    #[test]
+    fn finds_no_cycle_in_a_chain() {
+        let (a, b) = (rename_to("/d/a", "b"), rename_to("/d/b", "c"));
+        let to_do = vec![(PathBuf::from("/d/a"), &a), (PathBuf::from("/d/b"), &b)];
+        assert_eq!(find_rename_cycle(&to_do, Path::new("/d")), None);
+    }
+
+    #[test]
+    fn recovers_a_partially_applied_journal() {
+        let dir = test_dir("journal");
+        let (tmp_a, a) = (dir.join(".a.rcr-1-0"), dir.join("a"));
+        let (tmp_b, b) = (dir.join(".b.rcr-1-0"), dir.join("b"));
+        let mut journal = rename_journal::RenameJournal::new(&rename_journal::journal_path(&dir));
+        journal.record(&tmp_a, &a).unwrap();
+        journal.record(&tmp_b, &b).unwrap();
+        // a still has its temporary name, b was renamed back before the interruption
+        fs::write(&tmp_a, "a").unwrap();
+        fs::write(&b, "b").unwrap();
+        drop(journal);
+
+        recover_temporary_names(&dir, true);
+        assert!(tmp_a.exists() && !a.exists());
+        assert!(rename_journal::journal_path(&dir).exists());
+
+        recover_temporary_names(&dir, false);
+        assert_eq!(fs::read_to_string(&a).unwrap(), "a");
+        assert_eq!(fs::read_to_string(&b).unwrap(), "b");
+        assert!(!tmp_a.exists());
+        assert!(!rename_journal::journal_path(&dir).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn strips_copy_suffixes() {
+        assert_eq!(strip_copy_suffix("name.rar.1"), "name.rar");
+        assert_eq!(strip_copy_suffix("name.rar.12"), "name.rar");
+        assert_eq!(strip_copy_suffix("name (1).rar"), "name.rar");
+        assert_eq!(strip_copy_suffix("name(2).rar"), "name.rar");
+        assert_eq!(strip_copy_suffix("name (1)"), "name");
+        // Volume numbers and names merely containing parentheses are kept
+        assert_eq!(strip_copy_suffix("name.001"), "name.001");
+        assert_eq!(strip_copy_suffix("name ().rar"), "name ().rar");
+        assert_eq!(strip_copy_suffix("name (a).rar"), "name (a).rar");
+        assert_eq!(strip_copy_suffix("name.rar."), "name.rar.");
+    }
+
+    #[test]
+    fn finds_similar_names() {
+        assert!(is_similar_name("name.rar", "name.rar"));
+        assert!(is_similar_name("NAME.RAR", "name.rar"));
+        assert!(is_similar_name("Name.rar.1", "name.rar"));
+        assert!(is_similar_name("name (1).rar", "name.rar"));
+        assert!(is_similar_name("name", "name.rar"));
+        assert!(!is_similar_name("name.r00", "name.rar"));
+        assert!(!is_similar_name("name.001", "name.002"));
+        assert!(!is_similar_name("other.rar", "name.rar"));
+        assert!(!is_similar_name("name.rar", "name"));
+        assert!(!is_similar_name("name.zip", "name.rar"));
+        assert!(!is_similar_name(".rar", "name.rar"));
+    }
+
+    #[test]
+    fn trusts_similar_names_of_the_same_size() {
+        let dir = test_dir("named");
+        for (name, content) in &[("A.RAR", "aaaa"), ("b (1).rar", "bbbb"), ("c.rar.1", "cc"), ("d.rar", "dddd"), ("other.rar", "eeee")] {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        let sfv = "a.rar 00000000\nb.rar 00000000\nc.rar 00000000\nd.bin 00000000\n";
+        let mut catalog = sfv_reader::parse_sfv(&mut io::Cursor::new(sfv), "test.sfv").unwrap();
+        // The size of c.rar is known and does not match
+        catalog.entries[2].length = Some(4);
+        let catalogs = vec![catalog];
+        let mut files: Vec<PathBuf> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+        files.sort();
+
+        let (named, others) = get_named_candidates(files.clone(), &catalogs, false, false);
+        assert!(named.is_empty());
+        assert_eq!(others, files);
+
+        let (named, others) = get_named_candidates(files, &catalogs, true, false);
+        let named: Vec<&str> = named.iter().map(|e| e.filename.as_str()).collect();
+        let others: Vec<&str> = others.iter().map(|p| p.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(named, vec!["A.RAR", "b (1).rar"]);
+        assert_eq!(others, vec!["c.rar.1", "d.rar", "other.rar"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -5,4 +5,13 @@
 pub mod file_verification;
 pub mod utils;
 pub mod par2_reader;
-pub mod sfv_reader;
\ No newline at end of file
+pub mod sfv_reader;
+pub mod hash_cache;
+pub mod hash_engine;
+pub mod storage;
+pub mod hash_backend;
+pub mod concatenation;
+pub mod carving;
+pub mod throttle;
+pub mod duplicates;
+pub mod rename_journal;
\ No newline at end of file
@@ -37,6 +37,7 @@ const PAR2_PKT_TYPE_FILE_DESC: &[u8;16] = b"PAR 2.0\0FileDesc";
 const PAR2_PKT_TYPE_IFSC: &[u8;16] = b"PAR 2.0\0IFSC\0\0\0\0";
 const PAR2_PKT_TYPE_MAIN: &[u8;16] = b"PAR 2.0\0Main\0\0\0\0";
 const PAR2_PKT_TYPE_CREATOR: &[u8;16] = b"PAR 2.0\0Creator\0";
+const PAR2_PKT_TYPE_RECV_SLIC: &[u8;16] = b"PAR 2.0\0RecvSlic";
 
 static mut VERBOSE: bool = false;
 pub fn set_verbose(is: bool) { unsafe { VERBOSE = is; } }
@@ -49,6 +50,7 @@ enum Par2PacketTypes {
     Creator(Par2CreatorPacket),
     FileDescriptor(Par2FileDescriptorPacket),
     InputFileSliceChecksum(Par2InputFileSliceChecksumPacket),
+    RecoverySlice(Par2RecoverySlicePacket),
 }
 
 impl Default for Par2PacketTypes {
@@ -73,13 +75,21 @@ impl fmt::Debug for Par2PacketTypes {
             &Par2PacketTypes::InputFileSliceChecksum(ref pkt) => {
                 write!(f, "InputFileSliceChecksum({:?})", pkt)
             },
+            &Par2PacketTypes::RecoverySlice(ref pkt) => {
+                write!(f, "RecoverySlice({:?})", pkt)
+            },
         }
     }
 }
 
-const HEAD_LEN_I64: i64 = 8+8+16+16+16;
 const HEAD_LEN: usize = 8+8+16+16+16;
 
+// Packet bodies read into memory must not be larger than this. Recovery slices and
+// unknown packets are skipped without being read.
+const MAX_PARSED_PACKET_LEN: u64 = 64*1024*1024;
+// Slices are hashed in chunks, still a larger slice size is taken as damage
+const MAX_SLICE_SIZE: u64 = 1 << 31;
+
 #[derive(Default)]
 struct Par2PacketHead {
     pub magic: [u8;8],
@@ -136,17 +146,24 @@ impl fmt::Debug for Par2FileDescriptorPacket {
 #[derive(Default)]
 struct Par2InputFileSliceChecksumPacket {
     pub file_id: [u8;16],
-    // ?*20	{MD5 Hash, CRC32} MD5 Hash and CRC32 pairs for the slices
+    pub slice_checksums: Vec<file_verification::SliceChecksum>, // ?*20 {MD5 Hash, CRC32} pairs for the slices
 }
 
 impl fmt::Debug for Par2InputFileSliceChecksumPacket {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Par2InputFileSliceChecksumPacket {{ file_id: {} }}", 
+        write!(f, "Par2InputFileSliceChecksumPacket {{ file_id: {}, slices: {} }}", 
             utils::byte_array_to_hex(&self.file_id),
+            self.slice_checksums.len(),
         )
     }
 }
 
+#[derive(Default)] #[derive(Debug)]
+struct Par2RecoverySlicePacket {
+    pub exponent: u32,
+    // ?*4	byte array	Recovery data, skipped
+}
+
 impl fmt::Debug for Par2PacketHead {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Par2PacketHead {{ magic: '{}', len: {:?}, packet_hash: {}, recovery_set_id: {}, packet_type: '{}' }}", 
@@ -167,188 +184,648 @@ pub fn is_par2(filepath: &String) -> bool {
     false    
 }
 
-pub fn read_par2(filepath: &String) -> Result<file_verification::ChecksumCatalog, std::io::Error> {
+pub fn read_par2(filepath: &String) -> Result<file_verification::ChecksumCatalog, file_verification::ParseError> {
     return _read_par2(filepath, false);
 }
 
-fn _read_par2(filepath: &String, _check_only: bool) -> Result<file_verification::ChecksumCatalog, std::io::Error> {
+// Parses PAR2 packets from any source, e.g. for fuzzing the parser
+pub fn parse_par2<R: Read + Seek>(reader: &mut R, source_file: &str) -> Result<file_verification::ChecksumCatalog, file_verification::ParseError> {
+    let (valid, packets, warnings) = _read_par2_packets(reader, false)?;
+    Ok(_catalog_from_packets(source_file, valid, packets, warnings))
+}
+
+struct Par2Packet {
+    pub offset: u64,
+    pub head: Par2PacketHead,
+}
+
+// Reads all packets of a PAR2 file, returns false if the file is not a PAR2 file. A damaged
+// packet is skipped, reading stops at an invalid packet length. The packets read so far are
+// kept, e.g. of a partly downloaded volume, and the damage is returned as warnings.
+fn _read_par2_packets<R: Read + Seek>(reader: &mut R, _check_only: bool) -> Result<(bool, Vec<Par2Packet>, Vec<String>), file_verification::ParseError> {
+    let mut packets: Vec<Par2Packet> = Vec::new();
+    let mut warnings: Vec<String> = Vec::new();
+    let mut valid = true;
+
+    let stream_len = reader.seek(SeekFrom::End(0))?;
+    let mut offset = reader.seek(SeekFrom::Start(0))?;
+    let mut buf_head: [u8; HEAD_LEN] = [0; HEAD_LEN];
+
+    while offset < stream_len {
+        if stream_len - offset < HEAD_LEN as u64 {
+            if packets.is_empty() {
+                valid = false;
+            } else {
+                warnings.push(file_verification::ParseError::Truncated { offset }.to_string());
+            }
+            break;
+        }
+        reader.read_exact(&mut buf_head)?;
+
+        let mut head = _parse_par2_packet_head(&buf_head)?;
+        if_verbose(&|| println!("{:?}", head));
+
+        if &head.magic != PAR2_MAGIC {
+            valid = false;
+            break;
+        } else if _check_only {
+            valid = true;
+            break;
+        }
+
+        if head.len < HEAD_LEN as u64 || !head.len.is_multiple_of(4) {
+            warnings.push(file_verification::ParseError::InvalidPacketLength { offset, length: head.len }.to_string());
+            break;
+        }
+        if head.len > stream_len - offset {
+            warnings.push(file_verification::ParseError::Truncated { offset }.to_string());
+            break;
+        }
+
+        let len = head.len;
+        match _parse_par2_packet_body(&head, offset, reader) {
+            Ok(body) => {
+                head.packet_body = body;
+                if_verbose(&|| println!("{:?}", head.packet_body));
+                packets.push(Par2Packet { offset, head });
+            },
+            Err(file_verification::ParseError::Io(e)) => return Err(file_verification::ParseError::Io(e)),
+            Err(e) => warnings.push(format!("skipped packet: {}", e)),
+        }
+
+        offset = reader.seek(SeekFrom::Start(offset + len))?;
+    }
+
+    Ok((valid, packets, warnings))
+}
+
+fn _read_par2(filepath: &String, _check_only: bool) -> Result<file_verification::ChecksumCatalog, file_verification::ParseError> {
+    let mut fh = File::open(filepath)?;
+    let (valid, packets, warnings) = _read_par2_packets(&mut fh, _check_only)?;
+    Ok(_catalog_from_packets(filepath, valid, packets, warnings))
+}
+
+fn _catalog_from_packets(source_file: &str, valid: bool, packets: Vec<Par2Packet>, warnings: Vec<String>) -> file_verification::ChecksumCatalog {
     let mut catalog_file = file_verification::ChecksumCatalog {
-        valid: true,
+        valid,
         entries: Vec::new(),
         complete: false,
         source_type: file_verification::SourceTypes::PAR2,
-        source_file: filepath.to_string(),
+        source_file: source_file.to_string(),
         state: 0,
+        recovery_set_id: None,
+        volume_files: Vec::new(),
+        slice_size: None,
+        recovery_exponents: Vec::new(),
+        warnings,
     };
 
-    let fres: Result<File, std::io::Error> = match File::open(filepath) {
-        Ok(v) => Ok(v),
-        Err(_e) => return Err(_e)
-    };
-
-    let mut buf_head: [u8; HEAD_LEN] = [0; HEAD_LEN];
     let mut file_ids: Vec<[u8;16]> = Vec::new();
+    let mut slice_size: Option<u64> = None;
+    let mut slice_checksums: Vec<Par2InputFileSliceChecksumPacket> = Vec::new();
 
-    if fres.is_ok() {
-        let mut fh = fres.unwrap();
+    for packet in packets {
+        let head = packet.head;
 
-        loop {
-            let bytes = fh.read(&mut buf_head)?;
-            if bytes == 0 { break; }
-            if bytes < HEAD_LEN_I64 as usize {
-                    catalog_file.valid = false;
-                    break;
+        if catalog_file.recovery_set_id.is_none() {
+            catalog_file.recovery_set_id = Some(head.recovery_set_id);
+        }
+
+        if let Par2PacketTypes::Unknown = head.packet_body {
+            // NOP
+        } else if let Par2PacketTypes::Main(ref _body) = head.packet_body {
+            slice_size = Some(_body.slice_size);
+            catalog_file.slice_size = slice_size;
+
+        } else if let Par2PacketTypes::RecoverySlice(ref _body) = head.packet_body {
+            if !catalog_file.recovery_exponents.contains(&_body.exponent) {
+                catalog_file.recovery_exponents.push(_body.exponent);
             }
-            let head = _parse_par2_packet_head(&buf_head[0..bytes]);
-
-            if head.is_some() {
-                let mut head = head.unwrap();
-                if_verbose(&|| println!("{:?}", head));
-
-                if &head.magic != PAR2_MAGIC {
-                    catalog_file.valid = false;
-                    break;
-                } else if _check_only {
-                    catalog_file.valid = true;
-                    break;
-                }
 
-                head.packet_body = _parse_par2_packet_body(&head, &mut fh).unwrap();
-
-                if let Par2PacketTypes::Unknown = head.packet_body {
-                    // NOP
-                } else if let Par2PacketTypes::FileDescriptor(_body) = head.packet_body {
-                    let entry = file_verification::ChecksumEntry {
-                        filename: _body.name_of_file.to_string(),
-                        path: String::new(),
-                        checksum_crc32: None,
-                        checksum_md5: Some(_body.entire_file_md5),
-                        valid: true,
-                        state: 0,
-                    };
-
-                    if !file_ids.contains(&_body.file_id) {
-                        file_ids.push(_body.file_id);
-                        catalog_file.entries.push(entry);
-                    }
+        } else if let Par2PacketTypes::InputFileSliceChecksum(_body) = head.packet_body {
+            slice_checksums.push(_body);
+
+        } else if let Par2PacketTypes::FileDescriptor(_body) = head.packet_body {
+            let mut entry = file_verification::ChecksumEntry {
+                filename: _body.name_of_file.to_string(),
+                path: String::new(),
+                valid: file_verification::is_safe_filename(&_body.name_of_file),
+                state: 0,
+                file_id: Some(_body.file_id),
+                length: Some(_body.length_of_file),
+                first_16k_md5: Some(_body.first_16k_md5),
+                ..Default::default()
+            };
+            entry.set_digest(file_verification::HashAlgorithm::Md5, _body.entire_file_md5.to_vec());
 
-                    if_verbose(&|| println!("{:?}", _body));
+            if !file_ids.contains(&_body.file_id) {
+                file_ids.push(_body.file_id);
+                catalog_file.entries.push(entry);
+            }
+        }
+    }
 
-                } else {
-                    if_verbose(&|| println!("{:?}", head.packet_body));
-                } 
+    // A slice is never larger than the largest file (padded to a multiple of 4)
+    let max_length = catalog_file.entries.iter().filter_map(|e| e.length).max().unwrap_or(0);
+    if slice_size.is_some_and(|v| v > max_length.div_ceil(4) * 4) && !catalog_file.entries.is_empty() {
+        if_verbose(&|| println!("Ignoring slice size {:?} larger than all files", slice_size));
+        slice_size = None;
+        catalog_file.slice_size = None;
+        slice_checksums.clear();
+    }
 
-            } else {
-                catalog_file.valid = false;
-                break;
+    // IFSC packets may appear before or after the file descriptors
+    for ifsc in slice_checksums {
+        for entry in catalog_file.entries.iter_mut() {
+            if entry.file_id == Some(ifsc.file_id) && entry.slice_checksums.is_empty() {
+                entry.slice_size = slice_size;
+                entry.slice_checksums = ifsc.slice_checksums.clone();
+            }
+        }
+    }
+
+    catalog_file
+}
+
+// All packets of a PAR2 file for inspecting broken or unusual sets
+pub struct Par2Details {
+    pub source_file: String,
+    pub valid: bool,
+    packets: Vec<Par2Packet>,
+    warnings: Vec<String>,
+}
+
+pub fn read_par2_details(filepath: &String) -> Result<Par2Details, file_verification::ParseError> {
+    let mut fh = File::open(filepath)?;
+    let (valid, packets, warnings) = _read_par2_packets(&mut fh, false)?;
+    Ok(Par2Details {
+        source_file: filepath.to_string(),
+        valid: valid && !packets.is_empty(),
+        packets,
+        warnings,
+    })
+}
+
+impl Par2Details {
+    fn get_creator(&self) -> Option<&Par2CreatorPacket> {
+        self.packets.iter().filter_map(|p| match p.head.packet_body {
+            Par2PacketTypes::Creator(ref body) => Some(body),
+            _ => None,
+        }).next()
+    }
+
+    fn get_main(&self) -> Option<&Par2MainPacket> {
+        self.packets.iter().filter_map(|p| match p.head.packet_body {
+            Par2PacketTypes::Main(ref body) => Some(body),
+            _ => None,
+        }).next()
+    }
+
+    fn get_file_descriptors(&self) -> Vec<&Par2FileDescriptorPacket> {
+        let mut descriptors: Vec<&Par2FileDescriptorPacket> = Vec::new();
+        for packet in &self.packets {
+            if let Par2PacketTypes::FileDescriptor(ref body) = packet.head.packet_body {
+                if !descriptors.iter().any(|d| d.file_id == body.file_id) {
+                    descriptors.push(body);
+                }
             }
         }
+        descriptors
+    }
+
+    fn get_number_of_slices(&self, file_id: &[u8;16]) -> Option<usize> {
+        self.packets.iter().filter_map(|p| match p.head.packet_body {
+            Par2PacketTypes::InputFileSliceChecksum(ref body) if &body.file_id == file_id => Some(body.slice_checksums.len()),
+            _ => None,
+        }).next()
+    }
+
+    fn get_recovery_blocks(&self) -> usize {
+        let mut exponents: Vec<u32> = Vec::new();
+        for packet in &self.packets {
+            if let Par2PacketTypes::RecoverySlice(ref body) = packet.head.packet_body {
+                if !exponents.contains(&body.exponent) {
+                    exponents.push(body.exponent);
+                }
+            }
+        }
+        exponents.len()
+    }
+
+    fn get_unknown_packets(&self) -> Vec<&Par2Packet> {
+        self.packets.iter().filter(|p| matches!(p.head.packet_body, Par2PacketTypes::Unknown)).collect()
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+
+        text += &format!("PAR2 file '{}' ({})\n", self.source_file, if self.valid { "valid" } else { "invalid" });
+        text += &format!("Creator: {}\n", self.get_creator().map_or("-".to_string(), |c| format!("'{}'", c.client_identifier)));
+        text += &format!("Recovery set ID: {}\n", self.packets.first().map_or("-".to_string(), |p| utils::byte_array_to_hex(&p.head.recovery_set_id)));
+        text += &format!("Slice size: {}\n", self.get_main().map_or("-".to_string(), |m| m.slice_size.to_string()));
+        text += &format!("Recovery blocks: {}\n", self.get_recovery_blocks());
+
+        let descriptors = self.get_file_descriptors();
+        text += &format!("Files ({} of {} in recovery set):\n", descriptors.len(),
+            self.get_main().map_or("-".to_string(), |m| m.number_of_files.to_string()));
+        for (i, desc) in descriptors.iter().enumerate() {
+            text += &format!("[{}] {:?} slices: {}\n", i, desc,
+                self.get_number_of_slices(&desc.file_id).map_or("-".to_string(), |n| n.to_string()));
+        }
+
+        let unknown = self.get_unknown_packets();
+        text += &format!("Unknown packets ({}):\n", unknown.len());
+        for packet in unknown {
+            text += &format!("@{} '{}' len: {}\n", packet.offset, utils::printable_string_from(&packet.head.packet_type), packet.head.len);
+        }
+
+        text += &format!("Packets ({}):\n", self.packets.len());
+        for packet in &self.packets {
+            text += &format!("@{} {:?} {:?}\n", packet.offset, packet.head, packet.head.packet_body);
+        }
+        for warning in &self.warnings {
+            text += &format!("Damaged: {}\n", warning);
+        }
+
+        text
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+
+        json += "{";
+        json += &format!("\"file\":{},", utils::json_string(&self.source_file));
+        json += "\"type\":\"PAR2\",";
+        json += &format!("\"valid\":{},", self.valid);
+        json += &format!("\"creator\":{},", self.get_creator().map_or("null".to_string(), |c| utils::json_string(&c.client_identifier)));
+        json += &format!("\"recovery_set_id\":{},", self.packets.first().map_or("null".to_string(), |p| utils::json_string(&utils::byte_array_to_hex(&p.head.recovery_set_id))));
+        json += &format!("\"slice_size\":{},", self.get_main().map_or("null".to_string(), |m| m.slice_size.to_string()));
+        json += &format!("\"recovery_blocks\":{},", self.get_recovery_blocks());
+        json += &format!("\"number_of_files\":{},", self.get_main().map_or("null".to_string(), |m| m.number_of_files.to_string()));
+
+        let files: Vec<String> = self.get_file_descriptors().iter().map(|desc| {
+            format!("{{\"file_id\":{},\"name\":{},\"length\":{},\"first_16k_md5\":{},\"md5\":{},\"slices\":{}}}",
+                utils::json_string(&utils::byte_array_to_hex(&desc.file_id)),
+                utils::json_string(&desc.name_of_file),
+                desc.length_of_file,
+                utils::json_string(&utils::byte_array_to_hex(&desc.first_16k_md5)),
+                utils::json_string(&utils::byte_array_to_hex(&desc.entire_file_md5)),
+                self.get_number_of_slices(&desc.file_id).map_or("null".to_string(), |n| n.to_string()),
+            )
+        }).collect();
+        json += &format!("\"files\":[{}],", files.join(","));
+
+        let packet_to_json = |packet: &Par2Packet| {
+            format!("{{\"offset\":{},\"type\":{},\"length\":{}}}",
+                packet.offset,
+                utils::json_string(&utils::printable_string_from(&packet.head.packet_type)),
+                packet.head.len,
+            )
+        };
+        let unknown: Vec<String> = self.get_unknown_packets().iter().map(|p| packet_to_json(p)).collect();
+        json += &format!("\"unknown_packets\":[{}],", unknown.join(","));
+        let packets: Vec<String> = self.packets.iter().map(&packet_to_json).collect();
+        json += &format!("\"packets\":[{}],", packets.join(","));
+        let warnings: Vec<String> = self.warnings.iter().map(|w| utils::json_string(w)).collect();
+        json += &format!("\"warnings\":[{}]", warnings.join(","));
+        json += "}";
+
+        json
+    }
+}
+
+// PAR2 volumes of the same recovery set are merged into the catalog of the first
+// file of the set, preferring the index file over the .volNN+MM files.
+pub fn merge_recovery_sets(catalogs: Vec<file_verification::ChecksumCatalog>) -> Vec<file_verification::ChecksumCatalog> {
+    let mut merged: Vec<file_verification::ChecksumCatalog> = Vec::new();
+
+    for catalog in catalogs {
+        let set_index = match catalog.recovery_set_id {
+            Some(set_id) if catalog.source_type == file_verification::SourceTypes::PAR2 => {
+                merged.iter().position(|c| c.source_type == file_verification::SourceTypes::PAR2 && c.recovery_set_id == Some(set_id))
+            },
+            _ => None,
+        };
+
+        match set_index {
+            Some(idx) => {
+                let mut catalog = catalog;
+                if is_volume_file(&merged[idx].source_file) && !is_volume_file(&catalog.source_file) {
+                    std::mem::swap(&mut merged[idx].source_file, &mut catalog.source_file);
+                }
+                let target = &mut merged[idx];
+                target.volume_files.push(catalog.source_file);
+                target.volume_files.extend(catalog.volume_files);
+                target.valid = target.valid && catalog.valid;
+                target.warnings.extend(catalog.warnings);
+                if target.slice_size.is_none() {
+                    target.slice_size = catalog.slice_size;
+                }
+                for exponent in catalog.recovery_exponents {
+                    if !target.recovery_exponents.contains(&exponent) {
+                        target.recovery_exponents.push(exponent);
+                    }
+                }
+
+                for entry in catalog.entries {
+                    let known = target.entries.iter_mut().find(|e| e.file_id.is_some() && e.file_id == entry.file_id);
+                    match known {
+                        Some(known) => {
+                            if known.slice_checksums.is_empty() && !entry.slice_checksums.is_empty() {
+                                known.slice_size = entry.slice_size;
+                                known.slice_checksums = entry.slice_checksums;
+                            }
+                        },
+                        None => target.entries.push(entry),
+                    }
+                }
+                if_verbose(&|| println!("Merged into recovery set of {:?}", target.source_file));
+            },
+            None => merged.push(catalog),
+        }
     }
 
-    Ok(catalog_file)
+    merged
 }
 
-fn _parse_par2_packet_head(buffer: &[u8]) -> Option<Par2PacketHead> {
+// Recovery volumes are named like 'name.vol00+01.par2' or 'name.vol000-001.par2'
+fn is_volume_file(filepath: &str) -> bool {
+    let file_name = match std::path::Path::new(filepath).file_name().and_then(|n| n.to_str()) {
+        Some(v) => v.to_lowercase(),
+        None => return false,
+    };
+    let volume = match file_name.strip_suffix(".par2").and_then(|n| n.rfind(".vol").map(|pos| &n[pos + 4..])) {
+        Some(v) => v,
+        None => return false,
+    };
+    let mut numbers = volume.splitn(2, ['+', '-']);
+    match (numbers.next(), numbers.next()) {
+        (Some(first), Some(count)) => [first, count].iter()
+            .all(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit())),
+        _ => false,
+    }
+}
+
+fn _parse_par2_packet_head(buffer: &[u8; HEAD_LEN]) -> Result<Par2PacketHead, utils::DecodeError> {
     let mut head = Par2PacketHead {
         magic: Default::default(), // 0;8
-        len: 0, // 8;8
-        packet_hash: Default::default(), // 16:16
-        recovery_set_id: Default::default(), // 32:16
-        packet_type: Default::default(), // 48:16
+        len: utils::read_u64_le(&buffer[8..])?, // 8;8
+        packet_hash: utils::read_bytes_16(&buffer[16..])?, // 16:16
+        recovery_set_id: utils::read_bytes_16(&buffer[32..])?, // 32:16
+        packet_type: utils::read_bytes_16(&buffer[48..])?, // 48:16
         packet_body: Default::default(),
     };
 
-    // println!("buffer.len={:?} head.magic={:?} buffer[0..7].len={:?}", buffer.len(), head.magic, buffer[0..7].len());
-
     head.magic.copy_from_slice(&buffer[0..8]);
-    head.len = utils::slice_u8_to_u64(&buffer[8..16]);
-    head.packet_hash.copy_from_slice(&buffer[16..32]);
-    head.recovery_set_id.copy_from_slice(&buffer[32..48]);
-    head.packet_type.copy_from_slice(&buffer[48..64]);
 
-    Some(head)
+    Ok(head)
 }
 
-fn _parse_par2_packet_body(head: &Par2PacketHead, mut fh: &File) -> Option<Par2PacketTypes> {
+// Reads the body of a packet which is known to be complete and of a sane length. The
+// packet hash covers the recovery set id, the type and the body.
+fn _read_par2_packet_body<R: Read>(head: &Par2PacketHead, offset: u64, min_len: usize, reader: &mut R) -> Result<Vec<u8>, file_verification::ParseError> {
+    let body_len = head.len - HEAD_LEN as u64;
 
-    let to_skip = 0i64 + head.len as i64 - HEAD_LEN_I64;
+    if body_len > MAX_PARSED_PACKET_LEN {
+        return Err(file_verification::ParseError::PacketTooLarge { offset, length: head.len });
+    }
+    if body_len < min_len as u64 {
+        return Err(file_verification::ParseError::InvalidPacket { offset, reason: "packet body is too short" });
+    }
+
+    let mut buffer: Vec<u8> = vec![0; body_len as usize];
+    reader.read_exact(&mut buffer)?;
+
+    let mut hasher = file_verification::Hasher::new(file_verification::HashAlgorithm::Md5);
+    hasher.update(&head.recovery_set_id);
+    hasher.update(&head.packet_type);
+    hasher.update(&buffer);
+    if hasher.finish()[..] != head.packet_hash[..] {
+        return Err(file_verification::ParseError::InvalidPacket { offset, reason: "packet hash does not match" });
+    }
+    Ok(buffer)
+}
+
+fn _parse_par2_packet_body<R: Read>(head: &Par2PacketHead, offset: u64, reader: &mut R) -> Result<Par2PacketTypes, file_verification::ParseError> {
 
     let packet_type = match &head.packet_type {
         
         PAR2_PKT_TYPE_CREATOR => {
-            let mut body = Par2CreatorPacket {
-                client_identifier: String::new(),
+            let buffer = _read_par2_packet_body(head, offset, 0, reader)?;
+            let body = Par2CreatorPacket {
+                client_identifier: String::from_utf8_lossy(&buffer).trim_end_matches('\u{0}').to_string(),
             };
-            &fh.take(to_skip as u64).read_to_string(&mut body.client_identifier);
 
             Par2PacketTypes::Creator(body)
         },
 
         PAR2_PKT_TYPE_MAIN => {
-            let mut buffer_vec: Vec<u8> = vec![0;to_skip as usize];
-
-            buffer_vec.reserve_exact(to_skip as usize);
-            let buffer = buffer_vec.as_mut_slice();
-
-            &fh.take(to_skip as u64).read(buffer);
+            let buffer = _read_par2_packet_body(head, offset, 12, reader)?;
 
             let body = Par2MainPacket {
-                slice_size: utils::slice_u8_to_u64(&buffer[0..8]),
-                number_of_files: utils::slice_u8_to_u32(&buffer[8..12]),
+                slice_size: utils::read_u64_le(&buffer[0..])?,
+                number_of_files: utils::read_u32_le(&buffer[8..])?,
             };
 
+            if body.slice_size == 0 || !body.slice_size.is_multiple_of(4) || body.slice_size > MAX_SLICE_SIZE {
+                return Err(file_verification::ParseError::InvalidPacket { offset, reason: "invalid slice size" });
+            }
+
             Par2PacketTypes::Main(body)
         },
         
         PAR2_PKT_TYPE_IFSC => {
-            let mut buffer_vec: Vec<u8> = vec![0;to_skip as usize];
-            
-            buffer_vec.reserve_exact(to_skip as usize);
-            let buffer = buffer_vec.as_mut_slice();
+            let buffer = _read_par2_packet_body(head, offset, 16, reader)?;
 
-            &fh.take(to_skip as u64).read(buffer);
+            if (buffer.len() - 16) % 20 != 0 {
+                return Err(file_verification::ParseError::InvalidPacket { offset, reason: "incomplete slice checksum" });
+            }
 
             let mut body = Par2InputFileSliceChecksumPacket {
-                file_id: Default::default()
+                file_id: utils::read_bytes_16(&buffer[0..])?,
+                slice_checksums: Vec::new(),
             };
-            body.file_id.copy_from_slice(&buffer[0..16]);
+
+            for pair in buffer[16..].chunks(20) {
+                body.slice_checksums.push(file_verification::SliceChecksum {
+                    md5: utils::read_bytes_16(&pair[0..])?,
+                    crc32: utils::read_u32_le(&pair[16..])?,
+                });
+            }
+
             Par2PacketTypes::InputFileSliceChecksum(body)
         },
         
         PAR2_PKT_TYPE_FILE_DESC => {
-            let mut buffer_vec: Vec<u8> = vec![0;to_skip as usize];
-
-            buffer_vec.reserve_exact(to_skip as usize);
-            let buffer = buffer_vec.as_mut_slice();
-
-            &fh.take(to_skip as u64).read(buffer);
+            let buffer = _read_par2_packet_body(head, offset, 56, reader)?;
 
-            let filename = String::from_utf8(buffer[56..(to_skip) as usize].to_vec()).unwrap()
-                            .trim_end_matches('\u{0}') // was: 'trim_right_matches'
-                            .to_string();
-            // filename.retain(|c| c != '\u{0}');
+            let filename = match String::from_utf8(buffer[56..].to_vec()) {
+                Ok(v) => v.trim_end_matches('\u{0}').to_string(), // was: 'trim_right_matches'
+                Err(_e) => return Err(file_verification::ParseError::InvalidPacket { offset, reason: "file name is not valid UTF-8" }),
+            };
 
-            let mut body = Par2FileDescriptorPacket {
-                file_id: Default::default(),
-                entire_file_md5: Default::default(),
-                first_16k_md5: Default::default(),
-                length_of_file: utils::slice_u8_to_u64(&buffer[48..56]),
-                name_of_file: filename
-                ,
+            let body = Par2FileDescriptorPacket {
+                file_id: utils::read_bytes_16(&buffer[0..])?,
+                entire_file_md5: utils::read_bytes_16(&buffer[16..])?,
+                first_16k_md5: utils::read_bytes_16(&buffer[32..])?,
+                length_of_file: utils::read_u64_le(&buffer[48..])?,
+                name_of_file: filename,
             };
-            body.file_id.copy_from_slice(&buffer[0..16]);
-            body.entire_file_md5.copy_from_slice(&buffer[16..32]);
-            body.first_16k_md5.copy_from_slice(&buffer[32..48]);
 
             Par2PacketTypes::FileDescriptor(body)
         },
         
+        PAR2_PKT_TYPE_RECV_SLIC => {
+            // Only the exponent is read, the recovery data is skipped. Its hash is not
+            // checked, that would mean reading all recovery data of every volume.
+            if head.len < HEAD_LEN as u64 + 4 {
+                return Err(file_verification::ParseError::InvalidPacket { offset, reason: "packet body is too short" });
+            }
+            let mut buffer: [u8; 4] = [0; 4];
+            reader.read_exact(&mut buffer)?;
+
+            let body = Par2RecoverySlicePacket {
+                exponent: utils::read_u32_le(&buffer)?,
+            };
+
+            Par2PacketTypes::RecoverySlice(body)
+        },
+
         _ => Par2PacketTypes::Unknown,
     };
 
-    if let Par2PacketTypes::Unknown = packet_type {
-        fh.seek(SeekFrom::Current(to_skip)).unwrap();
+    Ok(packet_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const SET_ID: &[u8; 16] = b"0123456789abcdef";
+
+    fn packet(packet_type: &[u8; 16], body: &[u8]) -> Vec<u8> {
+        let mut hasher = file_verification::Hasher::new(file_verification::HashAlgorithm::Md5);
+        hasher.update(SET_ID);
+        hasher.update(packet_type);
+        hasher.update(body);
+
+        let mut data: Vec<u8> = Vec::new();
+        data.extend_from_slice(PAR2_MAGIC);
+        data.extend_from_slice(&((HEAD_LEN + body.len()) as u64).to_le_bytes());
+        data.extend_from_slice(&hasher.finish());
+        data.extend_from_slice(SET_ID);
+        data.extend_from_slice(packet_type);
+        data.extend_from_slice(body);
+        data
+    }
+
+    fn main_packet(slice_size: u64) -> Vec<u8> {
+        let mut body = slice_size.to_le_bytes().to_vec();
+        body.extend_from_slice(&[1, 0, 0, 0]);
+        packet(PAR2_PKT_TYPE_MAIN, &body)
+    }
+
+    fn creator_packet() -> Vec<u8> {
+        packet(PAR2_PKT_TYPE_CREATOR, b"test")
+    }
+
+    fn read(data: Vec<u8>) -> (bool, Vec<Par2Packet>, Vec<String>) {
+        _read_par2_packets(&mut Cursor::new(data), false).unwrap()
+    }
+
+    fn is_main(packet: &Par2Packet) -> bool {
+        matches!(packet.head.packet_body, Par2PacketTypes::Main(_))
+    }
+
+    #[test]
+    fn reads_valid_packets() {
+        let mut data = main_packet(4096);
+        data.extend(creator_packet());
+        let (valid, packets, warnings) = read(data);
+        assert!(valid);
+        assert_eq!(packets.len(), 2);
+        assert!(is_main(&packets[0]));
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn keeps_packets_before_a_truncated_packet() {
+        let mut data = main_packet(4096);
+        let mut creator = creator_packet();
+        creator.truncate(HEAD_LEN + 2);
+        data.extend(creator);
+        let (valid, packets, warnings) = read(data);
+        assert!(valid);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(warnings, vec![format!("file is truncated at offset {}", HEAD_LEN + 12)]);
+    }
+
+    #[test]
+    fn keeps_packets_before_a_truncated_header() {
+        let mut data = main_packet(4096);
+        data.extend_from_slice(&creator_packet()[..HEAD_LEN - 1]);
+        let (valid, packets, warnings) = read(data);
+        assert!(valid);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn stops_at_oversized_lengths() {
+        let mut data = main_packet(4096);
+        let mut creator = creator_packet();
+        creator[8..16].copy_from_slice(&(u64::MAX - 3).to_le_bytes());
+        data.extend(creator);
+        data.extend(creator_packet());
+        let (_valid, packets, warnings) = read(data);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("truncated"));
+    }
+
+    #[test]
+    fn stops_at_invalid_lengths() {
+        for length in [0u64, HEAD_LEN as u64 - 4, HEAD_LEN as u64 + 2].iter() {
+            let mut data = main_packet(4096);
+            let mut creator = creator_packet();
+            creator[8..16].copy_from_slice(&length.to_le_bytes());
+            data.extend(creator);
+            let (_valid, packets, warnings) = read(data);
+            assert_eq!(packets.len(), 1);
+            assert_eq!(warnings, vec![format!("invalid packet length {} at offset {}", length, HEAD_LEN + 12)]);
+        }
+    }
+
+    #[test]
+    fn skips_huge_and_invalid_slice_sizes() {
+        for slice_size in [0u64, 4097, MAX_SLICE_SIZE + 4, u64::MAX - 3].iter() {
+            let mut data = main_packet(*slice_size);
+            data.extend(creator_packet());
+            let (valid, packets, warnings) = read(data);
+            assert!(valid);
+            assert_eq!(packets.len(), 1);
+            assert!(!is_main(&packets[0]));
+            assert_eq!(warnings, vec!["skipped packet: invalid packet at offset 0: invalid slice size".to_string()]);
+        }
+    }
+
+    #[test]
+    fn skips_packets_with_a_wrong_hash() {
+        let mut data = main_packet(4096);
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        data.extend(creator_packet());
+        let (_valid, packets, warnings) = read(data);
+        assert_eq!(packets.len(), 1);
+        assert!(!is_main(&packets[0]));
+        assert_eq!(warnings, vec!["skipped packet: invalid packet at offset 0: packet hash does not match".to_string()]);
+    }
+
+    #[test]
+    fn rejects_files_without_packets() {
+        let (valid, packets, _warnings) = read(b"not a par2 file".to_vec());
+        assert!(!valid);
+        assert!(packets.is_empty());
     }
-    
-    Some(packet_type)
 }
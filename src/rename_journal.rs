@@ -0,0 +1,135 @@
+// reverse-checksum-renamer
+//
+// Copyright (C) 2020  Martin Feil aka. SGDW
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>
+
+// Files swapping their names (a -> b, b -> a) are renamed through a temporary name.
+// Each temporary name is written to a journal before the file is renamed, so the
+// files of an interrupted run get their names back on the next start.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{self, Path, PathBuf};
+use std::process;
+
+const JOURNAL_HEADER: &str = "# reverse-checksum-renamer journal v1";
+const JOURNAL_FILENAME: &str = ".reverse-checksum-renamer.journal";
+
+pub fn journal_path(dir: &Path) -> PathBuf {
+    dir.join(JOURNAL_FILENAME)
+}
+
+// An unused name next to the file, e.g. '.name.rcr-1234-0'
+pub fn temporary_path(file: &Path) -> PathBuf {
+    temporary_path_in(file.parent().unwrap_or_else(|| Path::new("")), file)
+}
+
+// An unused name for the file in another directory, e.g. the one it is renamed to
+pub fn temporary_path_in(dir: &Path, file: &Path) -> PathBuf {
+    let name = file.file_name().and_then(|n| n.to_str()).unwrap_or("file");
+    let mut n = 0;
+    loop {
+        let path = dir.join(format!(".{}.rcr-{}-{}", name, process::id(), n));
+        if !path.exists() {
+            return path;
+        }
+        n += 1;
+    }
+}
+
+// Created with the first temporary name, removed when all files have their final names
+pub struct RenameJournal {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl RenameJournal {
+    pub fn new(path: &Path) -> RenameJournal {
+        RenameJournal { path: path.to_path_buf(), file: None }
+    }
+
+    // Synced to disk before the file is renamed to the temporary name
+    pub fn record(&mut self, temporary: &Path, original: &Path) -> Result<(), io::Error> {
+        if self.file.is_none() {
+            let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            if file.metadata()?.len() == 0 {
+                writeln!(file, "{}", JOURNAL_HEADER)?;
+                sync_directory(&self.path)?;
+            }
+            self.file = Some(file);
+        }
+        // Absolute, the next run may be started in another directory
+        let temporary = path::absolute(temporary)?;
+        let original = path::absolute(original)?;
+        let file = self.file.as_mut().unwrap();
+        writeln!(file, "{}\t{}", temporary.to_str().unwrap(), original.to_str().unwrap())?;
+        file.sync_all()
+    }
+
+    pub fn remove(self) -> Result<(), io::Error> {
+        if self.file.is_some() {
+            drop(self.file);
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+// A new directory entry is only durable once its directory is synced
+fn sync_directory(file: &Path) -> Result<(), io::Error> {
+    match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+// The (temporary, original) paths of an interrupted run
+pub fn read_journal(path: &Path) -> Result<Vec<(PathBuf, PathBuf)>, io::Error> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut renames: Vec<(PathBuf, PathBuf)> = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(2, '\t');
+        if let (Some(temporary), Some(original)) = (parts.next(), parts.next()) {
+            renames.push((PathBuf::from(temporary), PathBuf::from(original)));
+        }
+    }
+    Ok(renames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn records_absolute_paths() {
+        let dir = env::temp_dir().join(format!("rcr-test-absolute-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = journal_path(&dir);
+        let mut journal = RenameJournal::new(&path);
+        journal.record(Path::new(".a.rcr-1-0"), Path::new("a")).unwrap();
+
+        let renames = read_journal(&path).unwrap();
+        assert_eq!(renames.len(), 1);
+        assert!(renames[0].0.is_absolute() && renames[0].0.ends_with(".a.rcr-1-0"));
+        assert!(renames[0].1.is_absolute() && renames[0].1.ends_with("a"));
+        journal.remove().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -15,19 +15,23 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>
 
-use std;
 use std::vec::Vec;
 
 use std::io::BufRead;
 use std::io::BufReader;
-use std::io::ErrorKind;
+use std::io::Read;
 
 use std::fs::File;
 
+use std::collections::HashMap;
+
+use utils;
 use file_verification;
 
 pub const EXTENSION: &str = "sfv";
 
+const MAX_LINE_LEN: u64 = 64*1024;
+
 pub fn is_sfv(filepath: &String) -> bool {
     let res = _read_sfv(filepath, true);
     if res.is_ok() {
@@ -44,58 +48,206 @@ pub fn is_sfv(filepath: &String) -> bool {
     false
 }
 
-pub fn read_sfv(filepath: &String) -> Result<file_verification::ChecksumCatalog, std::io::Error> {
+pub fn read_sfv(filepath: &String) -> Result<file_verification::ChecksumCatalog, file_verification::ParseError> {
     return _read_sfv(filepath, false);
 }
 
-fn _read_sfv(filepath: &String, check_only: bool) -> Result<file_verification::ChecksumCatalog, std::io::Error> {
+// Parses SFV lines from any source, e.g. for fuzzing the parser
+pub fn parse_sfv<R: BufRead>(reader: &mut R, source_file: &str) -> Result<file_verification::ChecksumCatalog, file_verification::ParseError> {
+    _parse_sfv(reader, source_file, false)
+}
+
+fn _read_sfv(filepath: &String, check_only: bool) -> Result<file_verification::ChecksumCatalog, file_verification::ParseError> {
+    let fh = File::open(filepath)?;
+    let mut file = BufReader::new(&fh);
+    _parse_sfv(&mut file, filepath, check_only)
+}
+
+fn _parse_sfv<R: BufRead>(reader: &mut R, source_file: &str, check_only: bool) -> Result<file_verification::ChecksumCatalog, file_verification::ParseError> {
     let mut catalog_file = file_verification::ChecksumCatalog {
         valid: true,
         entries: Vec::new(),
         complete: false,
         source_type: file_verification::SourceTypes::SFV,
-        source_file: filepath.to_string(),
+        source_file: source_file.to_string(),
         state: 0,
+        recovery_set_id: None,
+        volume_files: Vec::new(),
+        slice_size: None,
+        recovery_exponents: Vec::new(),
+        warnings: Vec::new(),
     };
 
-    let fres: Result<File, std::io::Error> = match File::open(filepath) {
-        Ok(v) => Ok(v),
-        Err(_e) => return Err(_e)
+    let mut sizes: HashMap<String, u64> = HashMap::new();
+    let mut line_number = 0;
+    while let Some(line) = _read_sfv_line(reader, line_number + 1)? {
+        line_number += 1;
+        if let Some(entry) = parse_sfv_line(&line) {
+            catalog_file.entries.push(entry);
+            if check_only {
+                return Ok(catalog_file);
+            }
+        } else if let Some((filename, size)) = parse_sfv_comment_size(&line) {
+            sizes.insert(filename, size);
+        }
+    }
+
+    for entry in catalog_file.entries.iter_mut() {
+        entry.length = sizes.get(&entry.filename).cloned();
+    }
+    Ok(catalog_file)
+}
+
+// Many tools list the files in comments, e.g. ";   3145728  12:34.56 2004-01-01 name.r00"
+pub fn parse_sfv_comment_size(line: &str) -> Option<(String, u64)> {
+    let mut rest = line.trim();
+    if !rest.starts_with(';') {
+        return None;
+    }
+    rest = &rest[1..];
+
+    let mut fields: Vec<&str> = Vec::new();
+    for _i in 0..3 {
+        rest = rest.trim_start();
+        let end = rest.find(char::is_whitespace)?;
+        fields.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+
+    let size = fields[0].parse::<u64>().ok()?;
+    let is_time = fields[1].contains(':') && fields[1].chars().all(|c| c.is_ascii_digit() || c == ':' || c == '.');
+    let is_date = fields[2].contains('-') && fields[2].chars().all(|c| c.is_ascii_digit() || c == '-');
+    let filename = rest.trim();
+
+    if is_time && is_date && !filename.is_empty() {
+        Some((filename.to_string(), size))
+    } else {
+        None
+    }
+}
+
+// Reads one line without its line break. Lines are limited in length, otherwise
+// checking a large binary file without line breaks would read it into memory.
+fn _read_sfv_line<R: BufRead>(reader: &mut R, line_number: usize) -> Result<Option<String>, file_verification::ParseError> {
+    let mut buffer: Vec<u8> = Vec::new();
+    // The longest line and its CR LF
+    let bytes = reader.by_ref().take(MAX_LINE_LEN + 2).read_until(b'\n', &mut buffer)?;
+    if bytes == 0 {
+        return Ok(None);
+    }
+
+    if buffer.last() == Some(&b'\n') {
+        buffer.pop();
+        if buffer.last() == Some(&b'\r') {
+            buffer.pop();
+        }
+    }
+    if buffer.len() as u64 > MAX_LINE_LEN {
+        return Err(file_verification::ParseError::LineTooLong { line: line_number });
+    }
+
+    match String::from_utf8(buffer) {
+        Ok(v) => Ok(Some(v)),
+        Err(_e) => Err(file_verification::ParseError::InvalidEncoding { line: line_number }),
+    }
+}
+
+// Every line of a SFV file with its line number for inspecting broken files
+pub struct SfvDetails {
+    pub source_file: String,
+    pub valid: bool,
+    pub lines: Vec<SfvLine>,
+}
+
+pub struct SfvLine {
+    pub line_number: usize,
+    pub text: String,
+    pub entry: Option<file_verification::ChecksumEntry>, // None for comments and empty lines
+}
+
+pub fn read_sfv_details(filepath: &String) -> Result<SfvDetails, file_verification::ParseError> {
+    let mut details = SfvDetails {
+        source_file: filepath.to_string(),
+        valid: false,
+        lines: Vec::new(),
     };
 
-    let fh = fres.unwrap();
-    let file = BufReader::new(&fh);
-    for rline in file.lines() {
-        if rline.is_ok() {
-            let line = rline.unwrap();
-            let entry = parse_sfv_line(&line);
-            if entry.is_some() {
-                catalog_file.entries.push(entry.unwrap());
-                if check_only {
-                    return Ok(catalog_file);
-                }
+    let fh = File::open(filepath)?;
+    let mut file = BufReader::new(&fh);
+    while let Some(line) = _read_sfv_line(&mut file, details.lines.len() + 1)? {
+        let entry = parse_sfv_line(&line);
+        if let Some(ref e) = entry {
+            details.valid = details.valid || (e.valid && e.get_crc32().is_some());
+        }
+        details.lines.push(SfvLine {
+            line_number: details.lines.len() + 1,
+            text: line,
+            entry,
+        });
+    }
+    Ok(details)
+}
+
+impl SfvDetails {
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        text += &format!("SFV file '{}' ({})\n", self.source_file, if self.valid { "valid" } else { "invalid" });
+        for line in &self.lines {
+            match line.entry {
+                Some(ref e) if e.valid && e.get_crc32().is_some() => {
+                    text += &format!("[line {}] '{}' crc32:{:08x}\n", line.line_number, e.filename, e.get_crc32().unwrap());
+                },
+                Some(_) => {
+                    text += &format!("[line {}] INVALID: '{}'\n", line.line_number, line.text);
+                },
+                None => {
+                    if !line.text.trim().is_empty() {
+                        text += &format!("[line {}] comment: '{}'\n", line.line_number, line.text);
+                    }
+                },
             }
-        } else {
-            return Err(std::io::Error::new(ErrorKind::Other, rline.err().unwrap()));
         }
+        text
+    }
+
+    pub fn to_json(&self) -> String {
+        let lines: Vec<String> = self.lines.iter()
+            .filter(|line| !line.text.trim().is_empty())
+            .map(|line| {
+                match line.entry {
+                    Some(ref e) if e.valid && e.get_crc32().is_some() => {
+                        format!("{{\"line\":{},\"filename\":{},\"crc32\":\"{:08x}\",\"valid\":true}}",
+                            line.line_number, utils::json_string(&e.filename), e.get_crc32().unwrap())
+                    },
+                    Some(_) => {
+                        format!("{{\"line\":{},\"text\":{},\"valid\":false}}",
+                            line.line_number, utils::json_string(&line.text))
+                    },
+                    None => {
+                        format!("{{\"line\":{},\"comment\":{}}}",
+                            line.line_number, utils::json_string(&line.text))
+                    },
+                }
+            }).collect();
+
+        format!("{{\"file\":{},\"type\":\"SFV\",\"valid\":{},\"lines\":[{}]}}",
+            utils::json_string(&self.source_file), self.valid, lines.join(","))
     }
-    Ok(catalog_file)
 }
 
 pub fn parse_sfv_line(line_par: &String) -> Option<file_verification::ChecksumEntry> {
     let mut entry = file_verification::ChecksumEntry {
         filename: String::new(),
         path: String::new(),
-        checksum_crc32: None,
-        checksum_md5: None,
         valid: true,
         state: 0,
+        ..Default::default()
     };
 
     let line = line_par.trim();
     let num_chars = line.chars().count();
 
-    if line.starts_with(';') {
+    if line.starts_with(';') || line.is_empty() {
         return None;
     }
 
@@ -126,8 +278,63 @@ pub fn parse_sfv_line(line_par: &String) -> Option<file_verification::ChecksumEn
     // println!("num_chars:{} i:{}", num_chars, i);
     if num_chars > i && num_chars > 0 && i > 0 {
         entry.filename = line_par.chars().take(num_chars-i-1).collect::<String>();
-        entry.checksum_crc32 = Some(u32::from_str_radix(&checksum, 16).unwrap());
+        let crc32 = u32::from_str_radix(&checksum, 16).ok();
+        if let Some(v) = crc32 {
+            entry.set_digest(file_verification::HashAlgorithm::Crc32, v.to_be_bytes().to_vec());
+        }
+        entry.valid = crc32.is_some() && file_verification::is_safe_filename(&entry.filename);
+    } else {
+        entry.valid = false;
     }
 
     Some(entry)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn reads_lines_without_line_breaks() {
+        let mut reader = Cursor::new(b"a.rar 12345678\r\nb.rar 9abcdef0\nlast".to_vec());
+        assert_eq!(_read_sfv_line(&mut reader, 1).unwrap(), Some("a.rar 12345678".to_string()));
+        assert_eq!(_read_sfv_line(&mut reader, 2).unwrap(), Some("b.rar 9abcdef0".to_string()));
+        assert_eq!(_read_sfv_line(&mut reader, 3).unwrap(), Some("last".to_string()));
+        assert_eq!(_read_sfv_line(&mut reader, 4).unwrap(), None);
+    }
+
+    #[test]
+    fn reads_the_longest_line() {
+        let mut data = vec![b'a'; MAX_LINE_LEN as usize];
+        data.extend_from_slice(b"\r\n");
+        let line = _read_sfv_line(&mut Cursor::new(data), 1).unwrap().unwrap();
+        assert_eq!(line.len() as u64, MAX_LINE_LEN);
+    }
+
+    #[test]
+    fn rejects_over_long_lines() {
+        let mut data = b"a.rar 12345678\n".to_vec();
+        data.extend(vec![b'a'; MAX_LINE_LEN as usize + 1]);
+        let mut reader = Cursor::new(data);
+        assert!(_read_sfv_line(&mut reader, 1).unwrap().is_some());
+        match _read_sfv_line(&mut reader, 2) {
+            Err(file_verification::ParseError::LineTooLong { line }) => assert_eq!(line, 2),
+            _ => panic!("expected LineTooLong"),
+        }
+    }
+
+    #[test]
+    fn rejects_binary_files() {
+        let mut data = vec![0u8; 2 * MAX_LINE_LEN as usize];
+        data[0] = 0xff;
+        assert!(parse_sfv(&mut Cursor::new(data), "binary").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_utf8() {
+        match _read_sfv_line(&mut Cursor::new(b"a\xff.rar 12345678\n".to_vec()), 1) {
+            Err(file_verification::ParseError::InvalidEncoding { line }) => assert_eq!(line, 1),
+            _ => panic!("expected InvalidEncoding"),
+        }
+    }
+}
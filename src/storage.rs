@@ -0,0 +1,67 @@
+// reverse-checksum-renamer
+//
+// Copyright (C) 2020  Martin Feil aka. SGDW
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>
+
+// Block devices backing the input files. Spinning disks are read by a single
+// reader, because concurrent reads make them seek.
+
+use std::fs;
+use std::path::Path;
+
+// Device and length of a file, None if it can't be read
+#[cfg(unix)]
+pub fn get_device_and_length(file: &str) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    let meta = fs::metadata(file).ok()?;
+    Some((meta.dev(), meta.len()))
+}
+
+#[cfg(not(unix))]
+pub fn get_device_and_length(file: &str) -> Option<(u64, u64)> {
+    let meta = fs::metadata(file).ok()?;
+    Some((0, meta.len()))
+}
+
+// Encoding of dev_t as glibc's gnu_dev_major() and gnu_dev_minor()
+pub fn device_major_minor(dev: u64) -> (u64, u64) {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    (major, minor)
+}
+
+pub fn device_name(dev: u64) -> String {
+    let (major, minor) = device_major_minor(dev);
+    format!("{}:{}", major, minor)
+}
+
+// The rotational flag of the device in /sys. Partitions have it in the queue of
+// their disk. None if unknown, e.g. for network or virtual file systems.
+pub fn is_rotational(dev: u64) -> Option<bool> {
+    let sys_path = fs::canonicalize(format!("/sys/dev/block/{}", device_name(dev))).ok()?;
+
+    let candidates = [
+        sys_path.join("queue").join("rotational"),
+        sys_path.parent().unwrap_or_else(|| Path::new("/")).join("queue").join("rotational"),
+    ];
+
+    for candidate in candidates.iter() {
+        if let Ok(content) = fs::read_to_string(candidate) {
+            return Some(content.trim() == "1");
+        }
+    }
+    None
+}
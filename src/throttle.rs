@@ -0,0 +1,91 @@
+// reverse-checksum-renamer
+//
+// Copyright (C) 2020  Martin Feil aka. SGDW
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>
+
+// Keeps background runs from starving other workloads, e.g. a NAS serving media:
+// a limit of the read rate shared by all threads, and the idle I/O and CPU priority.
+
+use std::io;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+extern crate libc;
+
+static MAX_READ_RATE: AtomicU64 = AtomicU64::new(0); // bytes per second, 0 is unlimited
+static NEXT_READ: Mutex<Option<Instant>> = Mutex::new(None);
+
+pub fn set_max_read_rate(bytes_per_sec: u64) {
+    MAX_READ_RATE.store(bytes_per_sec, Ordering::SeqCst);
+}
+
+// To be called after each read. Every read takes its share of the time from the end
+// of the previous one, so all readers together stay below the limit. Time not used
+// for reading is not saved up.
+pub fn consume(bytes: usize) {
+    let rate = MAX_READ_RATE.load(Ordering::Relaxed);
+    if rate == 0 || bytes == 0 {
+        return;
+    }
+
+    let now = Instant::now();
+    let until = {
+        let mut next_read = NEXT_READ.lock().unwrap();
+        let start = match *next_read {
+            Some(v) if v > now => v,
+            _ => now,
+        };
+        let until = start + Duration::from_secs_f64(bytes as f64 / rate as f64);
+        *next_read = Some(until);
+        until
+    };
+    if until > now {
+        thread::sleep(until - now);
+    }
+}
+
+// Idle I/O scheduling class and lowest CPU priority. Threads started afterwards
+// inherit both, so this has to be called before any work starts.
+#[cfg(target_os = "linux")]
+pub fn set_idle_priority() -> Result<(), io::Error> {
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+
+    let ioprio = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0 as libc::c_long, ioprio) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn set_idle_priority() -> Result<(), io::Error> {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn set_idle_priority() -> Result<(), io::Error> {
+    Err(io::Error::new(io::ErrorKind::Other, "not supported on this platform"))
+}
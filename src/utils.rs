@@ -15,6 +15,8 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>
 
+use std::fmt;
+
 pub fn printable_string_from(buffer: &[u8]) -> String {
     let mut str = String::new();
     for c in buffer.iter().cloned() {
@@ -37,18 +39,132 @@ pub fn byte_array_to_hex(bytes: &[u8]) -> String {
     str    
 }
 
-pub fn slice_u8_to_u64(buffer: &[u8]) -> u64 {
-    let mut val: u64 = 0;
-    for i in 0..7 {
-        val += (buffer[i] as u64) << (i*8);
+pub fn hex_to_byte_array(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len()).step_by(2)
+        .map(|i| hex.get(i..i+2).and_then(|v| u8::from_str_radix(v, 16).ok()))
+        .collect()
+}
+
+pub fn json_string(value: &str) -> String {
+    let mut str = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"'  => str += "\\\"",
+            '\\' => str += "\\\\",
+            '\n' => str += "\\n",
+            '\r' => str += "\\r",
+            '\t' => str += "\\t",
+            c if (c as u32) < 32 => str += &format!("\\u{:04x}", c as u32),
+            c => str.push(c),
+        }
+    }
+    str.push('"');
+    str
+}
+
+// Integer decoding for binary catalog formats (PAR2 and friends)
+
+#[derive(Debug, PartialEq)]
+pub struct DecodeError {
+    pub needed: usize,
+    pub available: usize,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} bytes needed but only {} available", self.needed, self.available)
     }
-    val
 }
 
-pub fn slice_u8_to_u32(buffer: &[u8]) -> u32 {
-    let mut val: u32 = 0;
-    for i in 0..3 {
-        val += (buffer[i] as u32) << (i*8);
+fn fixed_bytes<T: Default + AsMut<[u8]>>(buffer: &[u8]) -> Result<T, DecodeError> {
+    let mut bytes = T::default();
+    let needed = bytes.as_mut().len();
+    if buffer.len() < needed {
+        return Err(DecodeError { needed, available: buffer.len() });
     }
-    val
-}
\ No newline at end of file
+    bytes.as_mut().copy_from_slice(&buffer[0..needed]);
+    Ok(bytes)
+}
+
+pub fn read_u16_le(buffer: &[u8]) -> Result<u16, DecodeError> {
+    fixed_bytes(buffer).map(u16::from_le_bytes)
+}
+
+pub fn read_u32_le(buffer: &[u8]) -> Result<u32, DecodeError> {
+    fixed_bytes(buffer).map(u32::from_le_bytes)
+}
+
+pub fn read_u64_le(buffer: &[u8]) -> Result<u64, DecodeError> {
+    fixed_bytes(buffer).map(u64::from_le_bytes)
+}
+
+pub fn read_u16_be(buffer: &[u8]) -> Result<u16, DecodeError> {
+    fixed_bytes(buffer).map(u16::from_be_bytes)
+}
+
+pub fn read_u32_be(buffer: &[u8]) -> Result<u32, DecodeError> {
+    fixed_bytes(buffer).map(u32::from_be_bytes)
+}
+
+pub fn read_u64_be(buffer: &[u8]) -> Result<u64, DecodeError> {
+    fixed_bytes(buffer).map(u64::from_be_bytes)
+}
+
+// Hashes and IDs of binary formats, e.g. the 16 byte MD5 hashes of PAR2
+pub fn read_bytes_16(buffer: &[u8]) -> Result<[u8; 16], DecodeError> {
+    fixed_bytes(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_little_endian() {
+        let buffer = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0xff];
+        assert_eq!(read_u16_le(&buffer), Ok(0x0201));
+        assert_eq!(read_u32_le(&buffer), Ok(0x0403_0201));
+        assert_eq!(read_u64_le(&buffer), Ok(0x0807_0605_0403_0201));
+    }
+
+    #[test]
+    fn decodes_big_endian() {
+        let buffer = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0xff];
+        assert_eq!(read_u16_be(&buffer), Ok(0x0102));
+        assert_eq!(read_u32_be(&buffer), Ok(0x0102_0304));
+        assert_eq!(read_u64_be(&buffer), Ok(0x0102_0304_0506_0708));
+    }
+
+    #[test]
+    fn round_trips() {
+        for value in [0u64, 1, 0x8000_0000_0000_0000, u64::MAX, 0x0123_4567_89ab_cdef].iter() {
+            assert_eq!(read_u64_le(&value.to_le_bytes()), Ok(*value));
+            assert_eq!(read_u64_be(&value.to_be_bytes()), Ok(*value));
+            let value = *value as u32;
+            assert_eq!(read_u32_le(&value.to_le_bytes()), Ok(value));
+            assert_eq!(read_u32_be(&value.to_be_bytes()), Ok(value));
+            let value = value as u16;
+            assert_eq!(read_u16_le(&value.to_le_bytes()), Ok(value));
+            assert_eq!(read_u16_be(&value.to_be_bytes()), Ok(value));
+        }
+    }
+
+    #[test]
+    fn rejects_short_buffers() {
+        assert_eq!(read_u16_le(&[0x01]), Err(DecodeError { needed: 2, available: 1 }));
+        assert_eq!(read_u16_be(&[]), Err(DecodeError { needed: 2, available: 0 }));
+        assert_eq!(read_u32_le(&[0x01, 0x02, 0x03]), Err(DecodeError { needed: 4, available: 3 }));
+        assert_eq!(read_u32_be(&[0x01, 0x02, 0x03]), Err(DecodeError { needed: 4, available: 3 }));
+        assert_eq!(read_u64_le(&[0; 7]), Err(DecodeError { needed: 8, available: 7 }));
+        assert_eq!(read_u64_be(&[0; 7]), Err(DecodeError { needed: 8, available: 7 }));
+        assert_eq!(read_bytes_16(&[0; 15]), Err(DecodeError { needed: 16, available: 15 }));
+    }
+
+    #[test]
+    fn reads_16_bytes_from_a_longer_buffer() {
+        let buffer: Vec<u8> = (0..20).collect();
+        let expected: Vec<u8> = (0..16).collect();
+        assert_eq!(&read_bytes_16(&buffer).unwrap()[..], &expected[..]);
+        assert_eq!(fixed_bytes::<[u8; 4]>(&buffer[16..]), Ok([16, 17, 18, 19]));
+    }
+}